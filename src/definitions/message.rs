@@ -0,0 +1,324 @@
+use super::{Error, InvokeType, ScalarOpcode, Syscall, decode_args, encode_reply};
+use crate::syscall;
+
+/// Identifies the thread that sent a message. This is required in order to
+/// respond to a blocking message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MessageSender(pub(crate) usize);
+
+impl From<usize> for MessageSender {
+    fn from(src: usize) -> MessageSender {
+        MessageSender(src)
+    }
+}
+
+impl From<MessageSender> for usize {
+    fn from(src: MessageSender) -> usize {
+        src.0
+    }
+}
+
+/// A region of memory that was lent or moved into this process
+/// as part of a message.
+#[derive(Debug)]
+pub struct MemoryMessage {
+    pub(crate) addr: usize,
+    pub(crate) len: usize,
+    /// The first argument passed alongside the buffer, which is by
+    /// convention an offset into the buffer.
+    pub offset: usize,
+    /// The second argument passed alongside the buffer, which is by
+    /// convention the number of valid bytes in the buffer.
+    pub valid: usize,
+}
+
+impl MemoryMessage {
+    /// The address of the buffer within this process.
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// The length of the buffer, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the buffer is zero bytes long.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The contents of a message, which depends on how it was sent.
+#[derive(Debug)]
+pub enum MessageBody {
+    /// A buffer sent with `lend`, `lend_mut`, or `move`.
+    Memory(MemoryMessage),
+    /// The four arguments that follow the opcode in a `scalar` or
+    /// `blocking_scalar` message.
+    Scalar([usize; 4]),
+}
+
+/// A message that was received by a server.
+///
+/// If this envelope is dropped before a blocking message has been answered,
+/// the sender is sent a response automatically: `BlockingScalar` messages are
+/// answered with five zeroes, and lent memory is returned unchanged with an
+/// `offset` and `valid` of zero. Use [`MessageEnvelope::defer`] to answer the
+/// message at a later time instead. Memory that was moved into this process
+/// is unmapped when the envelope is dropped, unless it was taken out with
+/// `defer`.
+#[derive(Debug)]
+pub struct MessageEnvelope {
+    sender: MessageSender,
+    opcode: usize,
+    invoke_type: InvokeType,
+    body: MessageBody,
+}
+
+impl MessageEnvelope {
+    /// Decodes the registers returned by a `SyscallResult::Message`, where
+    /// `regs` is `$a1..=$a7`.
+    pub(crate) fn from_registers(regs: [usize; 7]) -> Result<Self, Error> {
+        let invoke_type = InvokeType::try_from(regs[1])?;
+        let body = match invoke_type {
+            InvokeType::LendMut | InvokeType::Lend | InvokeType::Move => {
+                MessageBody::Memory(MemoryMessage {
+                    addr: regs[3],
+                    len: regs[4],
+                    offset: regs[5],
+                    valid: regs[6],
+                })
+            }
+            InvokeType::Scalar | InvokeType::BlockingScalar => {
+                MessageBody::Scalar([regs[3], regs[4], regs[5], regs[6]])
+            }
+        };
+        Ok(MessageEnvelope {
+            sender: MessageSender(regs[0]),
+            opcode: regs[2],
+            invoke_type,
+            body,
+        })
+    }
+
+    /// The thread that sent this message.
+    pub fn sender(&self) -> MessageSender {
+        self.sender
+    }
+
+    /// The opcode the message was sent with.
+    pub fn opcode(&self) -> usize {
+        self.opcode
+    }
+
+    /// How the message was sent, which determines how it must be answered.
+    pub fn invoke_type(&self) -> InvokeType {
+        self.invoke_type
+    }
+
+    /// The arguments or buffer attached to this message. The body can only be
+    /// taken out of the envelope with `defer`, which also takes over answering
+    /// the message.
+    pub fn body(&self) -> &MessageBody {
+        &self.body
+    }
+
+    /// Responds to a `BlockingScalar` message with five values, which become
+    /// the result of the sender's call to `blocking_scalar`.
+    ///
+    /// Returns `Error::InvalidArgument` if this is not a `BlockingScalar`
+    /// message. The envelope is consumed either way, so in that case it is
+    /// dropped and the sender receives the automatic response described above.
+    /// Check [`MessageEnvelope::invoke_type`] first if that is not what you want.
+    pub fn return_scalar(self, args: [usize; 5]) -> Result<(), Error> {
+        if self.invoke_type != InvokeType::BlockingScalar {
+            return Err(Error::InvalidArgument);
+        }
+        let (sender, _) = self.defer();
+        crate::return_scalar(sender, args)
+    }
+
+    /// Responds to a `BlockingScalar` message for opcode `O` with its typed
+    /// reply. This behaves like `return_scalar` otherwise.
+    pub fn return_typed<O: ScalarOpcode>(self, reply: O::Reply) -> Result<(), Error> {
+        self.return_scalar(encode_reply(reply))
+    }
+
+    /// Returns lent memory to the sender, passing `offset` and `valid` back
+    /// as the result of its call to `lend` or `lend_mut`.
+    ///
+    /// Returns `Error::InvalidArgument` if this message was not sent with
+    /// `lend` or `lend_mut`. The envelope is consumed either way, so in that
+    /// case it is dropped and the sender receives the automatic response
+    /// described above. Check [`MessageEnvelope::invoke_type`] first if that is
+    /// not what you want.
+    pub fn return_memory(self, offset: usize, valid: usize) -> Result<(), Error> {
+        if !matches!(self.invoke_type, InvokeType::LendMut | InvokeType::Lend) {
+            return Err(Error::InvalidArgument);
+        }
+        match self.defer() {
+            (sender, MessageBody::Memory(range)) => {
+                crate::return_memory(sender, range, offset, valid)
+            }
+            (_, MessageBody::Scalar(_)) => Err(Error::InternalError),
+        }
+    }
+
+    /// Takes responsibility for answering this message, disabling the
+    /// automatic response that would otherwise be sent when it is dropped.
+    ///
+    /// The returned parts may be passed to `return_scalar` or `return_memory`
+    /// once the response is ready. If a blocking message is never answered,
+    /// the sender will wait forever. Memory that was moved into this process
+    /// is no longer unmapped automatically, and must be released with
+    /// `UnmapMemory` once it is no longer needed.
+    pub fn defer(self) -> (MessageSender, MessageBody) {
+        let this = core::mem::ManuallyDrop::new(self);
+        // `this` is never dropped, so the body is moved out exactly once.
+        (this.sender, unsafe { core::ptr::read(&this.body) })
+    }
+
+    /// Returns `true` if the sender is waiting for a response to this message.
+    pub fn is_blocking(&self) -> bool {
+        matches!(
+            self.invoke_type,
+            InvokeType::LendMut | InvokeType::Lend | InvokeType::BlockingScalar
+        )
+    }
+
+    /// Returns the scalar arguments, if this is a scalar message.
+    pub fn scalar_args(&self) -> Option<[usize; 4]> {
+        match self.body {
+            MessageBody::Scalar(args) => Some(args),
+            MessageBody::Memory(_) => None,
+        }
+    }
+
+    /// Decodes the arguments of a scalar message sent with opcode `O`.
+    ///
+    /// Returns `Error::InvalidArgument` if this message has a different opcode,
+    /// is not a scalar message, or does not hold a valid `O::Args`.
+    pub fn typed_args<O: ScalarOpcode>(&self) -> Result<O::Args, Error> {
+        if self.opcode != O::OPCODE {
+            return Err(Error::InvalidArgument);
+        }
+        decode_args(self.scalar_args().ok_or(Error::InvalidArgument)?)
+    }
+
+    /// Returns the attached memory region, if this is a memory message.
+    pub fn memory(&self) -> Option<&MemoryMessage> {
+        match &self.body {
+            MessageBody::Memory(mem) => Some(mem),
+            MessageBody::Scalar(_) => None,
+        }
+    }
+
+    /// Returns the attached buffer, if this is a memory message.
+    pub fn buf(&self) -> Option<&[u8]> {
+        let mem = self.memory()?;
+        if mem.len == 0 {
+            return Some(&[]);
+        }
+        // The kernel has mapped this range into our address space, and it
+        // stays mapped until the message is answered.
+        Some(unsafe {
+            core::slice::from_raw_parts(core::ptr::with_exposed_provenance(mem.addr), mem.len)
+        })
+    }
+
+    /// Returns the attached buffer mutably, if this message was sent with
+    /// `lend_mut` or `move`.
+    pub fn buf_mut(&mut self) -> Option<&mut [u8]> {
+        if !matches!(self.invoke_type, InvokeType::LendMut | InvokeType::Move) {
+            return None;
+        }
+        let MessageBody::Memory(mem) = &self.body else {
+            return None;
+        };
+        if mem.len == 0 {
+            return Some(&mut []);
+        }
+        Some(unsafe {
+            core::slice::from_raw_parts_mut(
+                core::ptr::with_exposed_provenance_mut(mem.addr),
+                mem.len,
+            )
+        })
+    }
+}
+
+impl Drop for MessageEnvelope {
+    fn drop(&mut self) {
+        // Errors are ignored here, since there is nobody left to report them to.
+        match (&self.invoke_type, &self.body) {
+            (InvokeType::BlockingScalar, _) => {
+                let _ = unsafe { syscall(Syscall::ReturnScalar, self.sender.0, 0, 0, 0, 0, 0, 0) };
+            }
+            (InvokeType::LendMut | InvokeType::Lend, MessageBody::Memory(mem)) => {
+                let _ = unsafe {
+                    syscall(
+                        Syscall::ReturnMemory,
+                        self.sender.0,
+                        mem.addr,
+                        mem.len,
+                        0,
+                        0,
+                        0,
+                        0,
+                    )
+                };
+            }
+            (InvokeType::Move, MessageBody::Memory(mem)) if mem.len != 0 => {
+                let _ = unsafe { syscall(Syscall::UnmapMemory, mem.addr, mem.len, 0, 0, 0, 0, 0) };
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn dropped_envelope_replies() {
+        let sid = create_server_with_address(address("mock-drop")).unwrap();
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                drop(receive_message(sid).unwrap());
+            }
+        });
+        let cid = connect(address("mock-drop")).unwrap();
+        assert_eq!(blocking_scalar(cid, [1, 2, 3, 4, 5]).unwrap(), [0; 5]);
+        let buf = PageBuf::<1>::from_slice(b"unchanged").unwrap();
+        assert_eq!(lend_pages(cid, 2, &buf, 5, 6).unwrap(), (0, 0));
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    #[test]
+    fn dropped_envelope_unmaps_moved_memory() {
+        let sid = create_server_with_address(address("mock-drop-move")).unwrap();
+        let server = thread::spawn(move || {
+            let envelope = receive_message(sid).unwrap();
+            assert_eq!(envelope.invoke_type(), InvokeType::Move);
+            let MessageBody::Memory(mem) = envelope.body() else {
+                panic!("expected a memory message");
+            };
+            let (addr, len) = (mem.addr(), mem.len());
+            drop(envelope);
+            unsafe { syscall(Syscall::UnmapMemory, addr, len, 0, 0, 0, 0, 0) }
+        });
+        let cid = connect(address("mock-drop-move")).unwrap();
+        move_pages(cid, 1, HeapPageBuf::from_slice(b"moved").unwrap(), 0, 0).unwrap();
+        assert!(matches!(server.join().unwrap(), Err(Error::BadAddress)));
+        destroy_server(sid).unwrap();
+    }
+}
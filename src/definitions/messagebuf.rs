@@ -0,0 +1,369 @@
+//! A small encoding layer for the contents of lent buffers, so that clients and
+//! servers do not need to hand-roll byte offsets.
+//!
+//! # Wire layout
+//!
+//! Values are written one after another from the start of the buffer, with no
+//! header, version or type information, so the client and server must agree on
+//! the sequence of values:
+//!
+//! * Integers are little-endian, and are aligned to their own size by inserting
+//!   zeroed padding before them, exactly as a `#[repr(C)]` struct would be laid
+//!   out. `bool` is a single byte that is either `0` or `1`.
+//! * [MessageBuf::put_str] and [MessageBuf::put_slice] write a `u32` length
+//!   followed by the bytes.
+//! * [MessageBuf::put_fixed_str] writes the string padded with zeroes to a fixed
+//!   capacity, followed by its length as a `u32`. This matches the archived form
+//!   of `xous_ipc::String<N>` used by many Xous services.
+//!
+//! Any bytes after the last value are zero, so nothing is leaked to the server.
+
+use super::{Connection, Error, PageAligned, PageBuf};
+
+/// Writes typed values into a buffer using the documented wire layout.
+///
+/// By default this owns a single zeroed page, which may be lent directly. It
+/// can also wrap any other buffer with [MessageBuf::new_in], such as a buffer
+/// received from a client that a server wants to write its response into.
+pub struct MessageBuf<B = PageBuf<1>> {
+    data: B,
+    len: usize,
+}
+
+impl<const PAGES: usize> MessageBuf<PageBuf<PAGES>> {
+    /// Creates an empty buffer of `PAGES` zeroed pages.
+    pub const fn new() -> Self {
+        MessageBuf {
+            data: PageBuf::new(),
+            len: 0,
+        }
+    }
+
+    /// Lends the buffer to the server, blocking if the mailbox is full.
+    pub fn lend(
+        &self,
+        connection: Connection,
+        opcode: usize,
+        arg1: usize,
+        arg2: usize,
+    ) -> Result<(usize, usize), Error> {
+        crate::lend_pages(connection, opcode, &self.data, arg1, arg2)
+    }
+
+    /// Mutably lends the buffer to the server, blocking if the mailbox is full.
+    /// Use [MessageBuf::reader_at] afterwards to decode the response.
+    pub fn lend_mut(
+        &mut self,
+        connection: Connection,
+        opcode: usize,
+        arg1: usize,
+        arg2: usize,
+    ) -> Result<(usize, usize), Error> {
+        crate::lend_pages_mut(connection, opcode, &mut self.data, arg1, arg2)
+    }
+}
+
+impl<const PAGES: usize> Default for MessageBuf<PageBuf<PAGES>> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> MessageBuf<B> {
+    /// Writes values into `data`, starting from the beginning.
+    pub fn new_in(data: B) -> Self {
+        MessageBuf { data, len: 0 }
+    }
+
+    /// The number of bytes written so far, which is typically passed to the
+    /// server as the `valid` argument.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if nothing has been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the whole underlying buffer.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    /// Returns the underlying buffer.
+    pub fn into_inner(self) -> B {
+        self.data
+    }
+
+    /// Returns a reader for the start of the buffer.
+    pub fn reader(&self) -> MessageReader<'_> {
+        MessageReader::new(self.data.as_ref())
+    }
+
+    /// Returns a reader starting at `offset`, such as the offset returned by a
+    /// server alongside its response.
+    pub fn reader_at(&self, offset: usize) -> MessageReader<'_> {
+        MessageReader::at(self.data.as_ref(), offset)
+    }
+
+    /// Pads with zeroes until the length is a multiple of `align`.
+    pub fn align_to(&mut self, align: usize) -> Result<&mut Self, Error> {
+        let padding = self.len.next_multiple_of(align) - self.len;
+        self.reserve(padding)?.fill(0);
+        Ok(self)
+    }
+
+    fn reserve(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        let start = self.len;
+        let end = start.checked_add(len).ok_or(Error::OutOfMemory)?;
+        let dest = self
+            .data
+            .as_mut()
+            .get_mut(start..end)
+            .ok_or(Error::OutOfMemory)?;
+        self.len = end;
+        Ok(dest)
+    }
+
+    fn put_aligned(&mut self, bytes: &[u8]) -> Result<&mut Self, Error> {
+        self.align_to(bytes.len())?;
+        self.reserve(bytes.len())?.copy_from_slice(bytes);
+        Ok(self)
+    }
+
+    /// Appends a `u8`.
+    pub fn put_u8(&mut self, value: u8) -> Result<&mut Self, Error> {
+        self.put_aligned(&[value])
+    }
+
+    /// Appends a `bool` as a single byte.
+    pub fn put_bool(&mut self, value: bool) -> Result<&mut Self, Error> {
+        self.put_u8(value as u8)
+    }
+
+    /// Appends a `u16`, aligned to two bytes.
+    pub fn put_u16(&mut self, value: u16) -> Result<&mut Self, Error> {
+        self.put_aligned(&value.to_le_bytes())
+    }
+
+    /// Appends a `u32`, aligned to four bytes.
+    pub fn put_u32(&mut self, value: u32) -> Result<&mut Self, Error> {
+        self.put_aligned(&value.to_le_bytes())
+    }
+
+    /// Appends an `i32`, aligned to four bytes.
+    pub fn put_i32(&mut self, value: i32) -> Result<&mut Self, Error> {
+        self.put_aligned(&value.to_le_bytes())
+    }
+
+    /// Appends a `u64`, aligned to eight bytes.
+    pub fn put_u64(&mut self, value: u64) -> Result<&mut Self, Error> {
+        self.put_aligned(&value.to_le_bytes())
+    }
+
+    /// Appends an `i64`, aligned to eight bytes.
+    pub fn put_i64(&mut self, value: i64) -> Result<&mut Self, Error> {
+        self.put_aligned(&value.to_le_bytes())
+    }
+
+    /// Appends `data` preceded by its length as a `u32`.
+    pub fn put_slice(&mut self, data: &[u8]) -> Result<&mut Self, Error> {
+        let len = u32::try_from(data.len()).map_err(|_| Error::OutOfMemory)?;
+        self.put_u32(len)?;
+        self.reserve(data.len())?.copy_from_slice(data);
+        Ok(self)
+    }
+
+    /// Appends `value` preceded by its length as a `u32`.
+    pub fn put_str(&mut self, value: &str) -> Result<&mut Self, Error> {
+        self.put_slice(value.as_bytes())
+    }
+
+    /// Appends `value` padded with zeroes to `capacity` bytes, followed by its
+    /// length as a `u32`.
+    ///
+    /// Returns `Error::InvalidString` if `value` is longer than `capacity`.
+    pub fn put_fixed_str(&mut self, value: &str, capacity: usize) -> Result<&mut Self, Error> {
+        if value.len() > capacity {
+            return Err(Error::InvalidString);
+        }
+        let dest = self.reserve(capacity)?;
+        dest[..value.len()].copy_from_slice(value.as_bytes());
+        dest[value.len()..].fill(0);
+        self.put_u32(value.len() as u32)
+    }
+}
+
+impl<const PAGES: usize> PageAligned for MessageBuf<PageBuf<PAGES>> {
+    fn as_bytes(&self) -> &[u8] {
+        self.data.as_bytes()
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        self.data.as_bytes_mut()
+    }
+}
+
+/// Reads typed values from a buffer that uses the documented wire layout.
+///
+/// Reads past the end of the buffer, and values that are not valid for their
+/// type, return `Error::SerializationError`.
+pub struct MessageReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MessageReader<'a> {
+    /// Reads from the start of `data`, such as the buffer attached to a
+    /// received message.
+    pub fn new(data: &'a [u8]) -> Self {
+        MessageReader { data, pos: 0 }
+    }
+
+    /// Reads from `data` starting at `offset`. Alignment is still measured from
+    /// the start of `data`.
+    pub fn at(data: &'a [u8], offset: usize) -> Self {
+        MessageReader { data, pos: offset }
+    }
+
+    /// The offset of the next value to be read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Skips to the next multiple of `align`.
+    pub fn align_to(&mut self, align: usize) -> Result<(), Error> {
+        let padding = self.pos.next_multiple_of(align) - self.pos;
+        self.take(padding)?;
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(len).ok_or(Error::SerializationError)?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(Error::SerializationError)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_aligned<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        self.align_to(N)?;
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    /// Reads a `u8`.
+    pub fn get_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take_aligned::<1>()?[0])
+    }
+
+    /// Reads a `bool`, which must be `0` or `1`.
+    pub fn get_bool(&mut self) -> Result<bool, Error> {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::SerializationError),
+        }
+    }
+
+    /// Reads a `u16`.
+    pub fn get_u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.take_aligned()?))
+    }
+
+    /// Reads a `u32`.
+    pub fn get_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take_aligned()?))
+    }
+
+    /// Reads an `i32`.
+    pub fn get_i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.take_aligned()?))
+    }
+
+    /// Reads a `u64`.
+    pub fn get_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take_aligned()?))
+    }
+
+    /// Reads an `i64`.
+    pub fn get_i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.take_aligned()?))
+    }
+
+    /// Reads bytes written by [MessageBuf::put_slice].
+    pub fn get_slice(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a string written by [MessageBuf::put_str].
+    pub fn get_str(&mut self) -> Result<&'a str, Error> {
+        core::str::from_utf8(self.get_slice()?).map_err(|_| Error::SerializationError)
+    }
+
+    /// Reads a string written by [MessageBuf::put_fixed_str] with the same
+    /// `capacity`.
+    pub fn get_fixed_str(&mut self, capacity: usize) -> Result<&'a str, Error> {
+        let bytes = self.take(capacity)?;
+        let len = self.get_u32()? as usize;
+        let bytes = bytes.get(..len).ok_or(Error::SerializationError)?;
+        core::str::from_utf8(bytes).map_err(|_| Error::SerializationError)
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn message_buf_round_trip() {
+        let sid = create_server_with_address(address("mock-message-buf")).unwrap();
+        let server = thread::spawn(move || {
+            let mut envelope = receive_message(sid).unwrap();
+            let buf = envelope.buf_mut().unwrap();
+            let mut request = MessageReader::new(buf);
+            assert_eq!(request.get_u8().unwrap(), 7);
+            assert_eq!(request.get_u64().unwrap(), u64::MAX - 1);
+            assert_eq!(request.get_str().unwrap(), "hello");
+            assert_eq!(request.get_fixed_str(16).unwrap(), "fixed");
+            assert!(request.get_bool().unwrap());
+            assert_eq!(request.position(), 49);
+
+            let mut response = MessageBuf::new_in(buf);
+            response.put_i32(-5).unwrap().put_slice(b"ok").unwrap();
+            envelope.return_memory(0, 10).unwrap();
+        });
+        let cid = connect(address("mock-message-buf")).unwrap();
+        let mut buf = MessageBuf::<PageBuf<1>>::new();
+        buf.put_u8(7)
+            .unwrap()
+            .put_u64(u64::MAX - 1)
+            .unwrap()
+            .put_str("hello")
+            .unwrap()
+            .put_fixed_str("fixed", 16)
+            .unwrap()
+            .put_bool(true)
+            .unwrap();
+        assert!(matches!(
+            buf.put_fixed_str("too long", 4),
+            Err(Error::InvalidString)
+        ));
+        let valid = buf.len();
+        assert_eq!(buf.lend_mut(cid, 0, 0, valid).unwrap(), (0, 10));
+
+        let mut response = buf.reader();
+        assert_eq!(response.get_i32().unwrap(), -5);
+        assert_eq!(response.get_slice().unwrap(), b"ok");
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+}
@@ -1,9 +1,21 @@
-#[cfg(feature = "unstable_mem")]
+#[cfg(any(feature = "unstable_mem", feature = "global_allocator"))]
 mod memoryflags;
 
-#[cfg(feature = "unstable_mem")]
+#[cfg(any(feature = "unstable_mem", feature = "global_allocator"))]
 pub use memoryflags::*;
 
+mod message;
+pub use message::*;
+
+mod messagebuf;
+pub use messagebuf::*;
+
+mod pagebuf;
+pub use pagebuf::*;
+
+mod scalar;
+pub use scalar::*;
+
 /// Indicates a particular syscall number as used by the Xous kernel.
 #[derive(Copy, Clone)]
 #[repr(usize)]
@@ -11,6 +23,7 @@ pub enum Syscall {
     MapMemory = 2,
     Yield = 3,
     UpdateMemoryFlags = 12,
+    CreateServerWithAddress = 14,
     ReceiveMessage = 15,
     SendMessage = 16,
     Connect = 17,
@@ -20,7 +33,11 @@ pub enum Syscall {
     TerminateProcess = 22,
     TrySendMessage = 24,
     TryConnect = 25,
+    ReturnScalar1 = 26,
+    ReturnScalar2 = 27,
+    CreateServer = 29,
     GetThreadId = 32,
+    DestroyServer = 34,
     Disconnect = 35,
     JoinThread = 36,
     AdjustProcessLimit = 38,
@@ -40,6 +57,8 @@ pub enum SyscallResult {
     MemoryRange = 3,
     /// A `u32` connection ID stored in $a1
     ConnectionId = 7,
+    /// A server ID stored in $a1..=$a4, along with a connection to it in $a5
+    NewServerId = 8,
     /// A message was received
     Message = 9,
     /// A `u32` thread id stored in $a1
@@ -116,6 +135,21 @@ pub enum Error {
     SecurityError = 39,
 }
 
+impl Error {
+    /// Returns `true` if the operation that failed with this error may succeed
+    /// if it is tried again unchanged, because the error describes a resource
+    /// that is temporarily busy rather than a problem with the request.
+    ///
+    /// `Timeout` is not considered transient, since it is returned once a
+    /// caller has already decided to stop waiting.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::ServerQueueFull | Error::ThreadNotAvailable | Error::Unavailable
+        )
+    }
+}
+
 impl From<usize> for Error {
     // Marking this function as "cold" ensures this error path
     // does not get inlined.
@@ -239,13 +273,15 @@ impl core::fmt::Debug for Error {
 impl core::error::Error for Error {}
 
 /// Indicates the type of Message that is sent when making a `SendMessage` syscall.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum InvokeType {
     /// Mutably lend the buffer to the server
     LendMut = 1,
     /// Immutably lend
     Lend = 2,
-    #[cfg(feature = "unstable_mem")]
-    /// Move the buffer from this process into the server
+    /// Move the buffer from this process into the server. Sending this type
+    /// of message requires the `unstable_mem` feature, however any server
+    /// may receive one.
     Move = 3,
     /// Send a scalar message to the server without blocking
     Scalar = 4,
@@ -253,6 +289,20 @@ pub enum InvokeType {
     BlockingScalar = 5,
 }
 
+impl TryFrom<usize> for InvokeType {
+    type Error = Error;
+    fn try_from(src: usize) -> Result<Self, Self::Error> {
+        match src {
+            1 => Ok(InvokeType::LendMut),
+            2 => Ok(InvokeType::Lend),
+            3 => Ok(InvokeType::Move),
+            4 => Ok(InvokeType::Scalar),
+            5 => Ok(InvokeType::BlockingScalar),
+            _ => Err(Error::InvalidCoding),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 /// A representation of a connection to a Xous service.
 pub struct Connection(pub(crate) u32);
@@ -283,6 +333,54 @@ impl TryInto<usize> for Connection {
     }
 }
 
+/// A connection to a Xous service that is disconnected when dropped.
+///
+/// Unlike [Connection], this type is not `Copy`, so the connection can only be
+/// closed once. Note that the kernel hands out the same connection ID each time
+/// a process connects to a given server, so any [Connection] to the same server
+/// obtained elsewhere in this program will also stop working once this is dropped.
+#[derive(Debug)]
+pub struct OwnedConnection(Connection);
+
+impl OwnedConnection {
+    /// Takes ownership of `connection`, which will be disconnected when the
+    /// returned value is dropped.
+    ///
+    /// # Safety
+    ///
+    /// The connection must not be in use elsewhere in this program, since it
+    /// will be disconnected and its ID may be reused.
+    pub unsafe fn from_raw(connection: Connection) -> Self {
+        OwnedConnection(connection)
+    }
+
+    /// Releases ownership of the connection without disconnecting it. The
+    /// caller becomes responsible for calling `disconnect`.
+    pub fn into_raw(self) -> Connection {
+        let connection = self.0;
+        core::mem::forget(self);
+        connection
+    }
+
+    /// Keeps the connection open for the remainder of the program.
+    pub fn leak(self) -> Connection {
+        self.into_raw()
+    }
+}
+
+impl core::ops::Deref for OwnedConnection {
+    type Target = Connection;
+    fn deref(&self) -> &Connection {
+        &self.0
+    }
+}
+
+impl Drop for OwnedConnection {
+    fn drop(&mut self) {
+        let _ = unsafe { crate::disconnect(self.0) };
+    }
+}
+
 #[derive(Debug)]
 /// The specified Server address could not be parsed
 pub enum ServerAddressError {
@@ -290,6 +388,7 @@ pub enum ServerAddressError {
     InvalidLength,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct ServerAddress(pub(crate) [u32; 4]);
 
 impl TryFrom<&str> for ServerAddress {
@@ -319,6 +418,36 @@ impl Into<[u32; 4]> for ServerAddress {
     }
 }
 
+impl From<[u32; 4]> for ServerAddress {
+    fn from(src: [u32; 4]) -> ServerAddress {
+        ServerAddress(src)
+    }
+}
+
+/// The address that other processes use to `connect()` to a server.
+impl From<ServerId> for ServerAddress {
+    fn from(src: ServerId) -> ServerAddress {
+        ServerAddress(src.0)
+    }
+}
+
+/// The address of a server running within this process. Messages sent
+/// to this server are obtained by calling `receive_message`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ServerId(pub(crate) [u32; 4]);
+
+impl From<[u32; 4]> for ServerId {
+    fn from(src: [u32; 4]) -> ServerId {
+        ServerId(src)
+    }
+}
+
+impl From<ServerId> for [u32; 4] {
+    fn from(src: ServerId) -> [u32; 4] {
+        src.0
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct ThreadId(usize);
 
@@ -334,10 +463,61 @@ impl Into<usize> for ThreadId {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 #[repr(usize)]
 /// Limits that can be passed to `AdjustLimit`
 pub enum Limits {
+    /// The largest the heap may grow to, in bytes.
     HeapMaximum = 1,
+    /// The current size of the heap, in bytes.
     HeapSize = 2,
 }
+
+/// An error from reading or changing a process limit with `get_limit` or
+/// `set_limit`.
+#[derive(Copy, Clone, Debug)]
+pub enum LimitError {
+    /// The kernel does not support this limit, and returned
+    /// `Error::InvalidLimit`.
+    Unsupported(Limits),
+    /// The kernel would not change the limit, for example because the new
+    /// value is out of range. It is still `current`.
+    Refused { knob: Limits, current: usize },
+    /// Any other error from the kernel.
+    Kernel(Error),
+}
+
+impl From<Error> for LimitError {
+    fn from(src: Error) -> Self {
+        LimitError::Kernel(src)
+    }
+}
+
+impl From<LimitError> for Error {
+    fn from(src: LimitError) -> Self {
+        match src {
+            LimitError::Unsupported(_) | LimitError::Refused { .. } => Error::InvalidLimit,
+            LimitError::Kernel(e) => e,
+        }
+    }
+}
+
+impl core::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LimitError::Unsupported(knob) => {
+                write!(f, "the kernel does not support the limit {:?}", knob)
+            }
+            LimitError::Refused { knob, current } => {
+                write!(
+                    f,
+                    "the kernel refused to change {:?} from {}",
+                    knob, current
+                )
+            }
+            LimitError::Kernel(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl core::error::Error for LimitError {}
@@ -0,0 +1,100 @@
+use super::Error;
+
+/// The size of a page of memory. Buffers that are lent or moved to another
+/// process must start on a page boundary and be a multiple of this size.
+pub const PAGE_SIZE: usize = 4096;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A buffer that is guaranteed to be page-aligned and a whole number of pages
+/// long, so that it can always be lent to another process.
+///
+/// This trait is sealed, and is implemented by [PageBuf], [MessageBuf](super::MessageBuf)
+/// and, with the `unstable_mem` feature, `HeapPageBuf`.
+pub trait PageAligned: private::Sealed {
+    /// Returns the buffer as a slice of bytes.
+    fn as_bytes(&self) -> &[u8];
+
+    /// Returns the buffer as a mutable slice of bytes.
+    fn as_bytes_mut(&mut self) -> &mut [u8];
+}
+
+/// A buffer of `PAGES` pages, suitable for placing on the stack or in a `static`.
+///
+/// The buffer is zeroed when it is created, so any bytes that are not
+/// explicitly written are never leaked to the server it is lent to.
+#[repr(C, align(4096))]
+pub struct PageBuf<const PAGES: usize> {
+    data: [[u8; PAGE_SIZE]; PAGES],
+}
+
+impl<const PAGES: usize> PageBuf<PAGES> {
+    /// Creates a new zeroed buffer.
+    pub const fn new() -> Self {
+        PageBuf {
+            data: [[0u8; PAGE_SIZE]; PAGES],
+        }
+    }
+
+    /// Creates a new buffer with `data` copied to the start, and the remainder
+    /// zeroed.
+    ///
+    /// Returns `Error::InvalidArgument` if `data` does not fit.
+    pub fn from_slice(data: &[u8]) -> Result<Self, Error> {
+        let mut buf = Self::new();
+        buf.as_bytes_mut()
+            .get_mut(..data.len())
+            .ok_or(Error::InvalidArgument)?
+            .copy_from_slice(data);
+        Ok(buf)
+    }
+}
+
+impl<const PAGES: usize> Default for PageBuf<PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const PAGES: usize> private::Sealed for PageBuf<PAGES> {}
+impl<const PAGES: usize> private::Sealed for super::MessageBuf<PageBuf<PAGES>> {}
+
+impl<const PAGES: usize> PageAligned for PageBuf<PAGES> {
+    fn as_bytes(&self) -> &[u8] {
+        self.data.as_flattened()
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        self.data.as_flattened_mut()
+    }
+}
+
+impl<const PAGES: usize> core::ops::Deref for PageBuf<PAGES> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<const PAGES: usize> core::ops::DerefMut for PageBuf<PAGES> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_bytes_mut()
+    }
+}
+
+impl<const PAGES: usize> AsRef<[u8]> for PageBuf<PAGES> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<const PAGES: usize> AsMut<[u8]> for PageBuf<PAGES> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_bytes_mut()
+    }
+}
+
+#[cfg(feature = "unstable_mem")]
+impl private::Sealed for crate::HeapPageBuf {}
@@ -0,0 +1,368 @@
+//! Typed arguments for scalar messages.
+//!
+//! A scalar message carries an opcode and four `usize` arguments, and a
+//! `blocking_scalar` is answered with five. [ScalarArg] describes how a single
+//! value is stored in those registers, and [ScalarArgs] packs a value or a
+//! tuple of values into them one after another. A [ScalarOpcode] ties an
+//! opcode to the types that are sent and returned with it, so that the client
+//! and server share a single signature.
+//!
+//! Values are stored so that both sides agree regardless of the width of
+//! `usize`:
+//!
+//! * Integers of 32 bits or fewer, `bool`, `char` and `f32` take one register.
+//!   Signed integers and floats are stored as their unsigned bit pattern.
+//! * `u64`, `i64` and `f64` take two registers, the low 32 bits first.
+//! * `usize` and `isize` take one register, and are passed through unchanged.
+//!
+//! Small enums may implement [ScalarArg] themselves, typically by converting
+//! to and from a `u32`.
+//!
+//! Payloads that do not fit are rejected when the program is compiled.
+
+use core::marker::PhantomData;
+
+use super::Error;
+
+/// A value that can be stored in one or more scalar registers.
+pub trait ScalarArg: Sized {
+    /// The number of registers the value takes.
+    const WORDS: usize;
+
+    /// Writes the value to the first `WORDS` entries of `words`.
+    fn encode(self, words: &mut [usize]);
+
+    /// Reads a value from the first `WORDS` entries of `words`.
+    ///
+    /// Returns `Error::InvalidArgument` if the registers do not hold a valid
+    /// value of this type.
+    fn decode(words: &[usize]) -> Result<Self, Error>;
+}
+
+/// A value, or a tuple of up to five values, that is packed into the
+/// registers of a scalar message.
+pub trait ScalarArgs: Sized {
+    /// The number of registers the values take in total.
+    const WORDS: usize;
+
+    /// Writes the values to the start of `words`.
+    fn encode(self, words: &mut [usize]);
+
+    /// Reads the values from the start of `words`.
+    fn decode(words: &[usize]) -> Result<Self, Error>;
+}
+
+/// Describes the arguments sent with an opcode, and the values it returns
+/// when it is sent with `blocking_scalar`. Both sides of a connection use the
+/// same description.
+///
+/// Clients send it with `typed_scalar` or `typed_blocking_scalar`, and servers
+/// decode it with `MessageEnvelope::typed_args` and answer it with
+/// `MessageEnvelope::return_typed`.
+pub trait ScalarOpcode {
+    /// The opcode of the message.
+    const OPCODE: usize;
+    /// The arguments, which must fit in four registers.
+    type Args: ScalarArgs;
+    /// The response to a `blocking_scalar`, which must fit in five registers.
+    /// Use `()` for opcodes that are only sent with `scalar`.
+    type Reply: ScalarArgs;
+}
+
+struct Fits<A, const N: usize>(PhantomData<A>);
+
+impl<A: ScalarArgs, const N: usize> Fits<A, N> {
+    const CHECK: () = assert!(
+        A::WORDS <= N,
+        "scalar payload does not fit in the registers"
+    );
+}
+
+/// Packs `args` into the four argument registers of a message.
+pub fn encode_args<A: ScalarArgs>(args: A) -> [usize; 4] {
+    #[allow(clippy::let_unit_value)]
+    let () = Fits::<A, 4>::CHECK;
+    let mut words = [0; 4];
+    args.encode(&mut words);
+    words
+}
+
+/// Packs `reply` into the five registers returned by `blocking_scalar`.
+pub fn encode_reply<A: ScalarArgs>(reply: A) -> [usize; 5] {
+    #[allow(clippy::let_unit_value)]
+    let () = Fits::<A, 5>::CHECK;
+    let mut words = [0; 5];
+    reply.encode(&mut words);
+    words
+}
+
+/// Unpacks arguments written by [encode_args].
+pub fn decode_args<A: ScalarArgs>(words: [usize; 4]) -> Result<A, Error> {
+    #[allow(clippy::let_unit_value)]
+    let () = Fits::<A, 4>::CHECK;
+    A::decode(&words)
+}
+
+/// Unpacks a reply written by [encode_reply].
+pub fn decode_reply<A: ScalarArgs>(words: [usize; 5]) -> Result<A, Error> {
+    #[allow(clippy::let_unit_value)]
+    let () = Fits::<A, 5>::CHECK;
+    A::decode(&words)
+}
+
+macro_rules! narrow_scalar_arg {
+    ($($t:ty => $bits:ty),* $(,)?) => {
+        $(
+            impl ScalarArg for $t {
+                const WORDS: usize = 1;
+
+                fn encode(self, words: &mut [usize]) {
+                    words[0] = self as $bits as usize;
+                }
+
+                fn decode(words: &[usize]) -> Result<Self, Error> {
+                    <$bits>::try_from(words[0])
+                        .map(|bits| bits as $t)
+                        .map_err(|_| Error::InvalidArgument)
+                }
+            }
+        )*
+    };
+}
+
+narrow_scalar_arg! {
+    u8 => u8,
+    u16 => u16,
+    u32 => u32,
+    i8 => u8,
+    i16 => u16,
+    i32 => u32,
+}
+
+impl ScalarArg for usize {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [usize]) {
+        words[0] = self;
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        Ok(words[0])
+    }
+}
+
+impl ScalarArg for isize {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [usize]) {
+        words[0] = self as usize;
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        Ok(words[0] as isize)
+    }
+}
+
+impl ScalarArg for bool {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [usize]) {
+        words[0] = self as usize;
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        match words[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidArgument),
+        }
+    }
+}
+
+impl ScalarArg for char {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [usize]) {
+        ScalarArg::encode(self as u32, words)
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        char::from_u32(<u32 as ScalarArg>::decode(words)?).ok_or(Error::InvalidArgument)
+    }
+}
+
+impl ScalarArg for f32 {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [usize]) {
+        ScalarArg::encode(self.to_bits(), words)
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        Ok(f32::from_bits(<u32 as ScalarArg>::decode(words)?))
+    }
+}
+
+impl ScalarArg for u64 {
+    const WORDS: usize = 2;
+
+    fn encode(self, words: &mut [usize]) {
+        ScalarArg::encode(self as u32, &mut words[0..1]);
+        ScalarArg::encode((self >> 32) as u32, &mut words[1..2]);
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        let low = <u32 as ScalarArg>::decode(&words[0..1])? as u64;
+        let high = <u32 as ScalarArg>::decode(&words[1..2])? as u64;
+        Ok(high << 32 | low)
+    }
+}
+
+impl ScalarArg for i64 {
+    const WORDS: usize = 2;
+
+    fn encode(self, words: &mut [usize]) {
+        ScalarArg::encode(self as u64, words)
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        Ok(<u64 as ScalarArg>::decode(words)? as i64)
+    }
+}
+
+impl ScalarArg for f64 {
+    const WORDS: usize = 2;
+
+    fn encode(self, words: &mut [usize]) {
+        ScalarArg::encode(self.to_bits(), words)
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        Ok(f64::from_bits(<u64 as ScalarArg>::decode(words)?))
+    }
+}
+
+impl<T: ScalarArg> ScalarArgs for T {
+    const WORDS: usize = <T as ScalarArg>::WORDS;
+
+    fn encode(self, words: &mut [usize]) {
+        ScalarArg::encode(self, words)
+    }
+
+    fn decode(words: &[usize]) -> Result<Self, Error> {
+        <T as ScalarArg>::decode(words)
+    }
+}
+
+impl ScalarArgs for () {
+    const WORDS: usize = 0;
+
+    fn encode(self, _words: &mut [usize]) {}
+
+    fn decode(_words: &[usize]) -> Result<Self, Error> {
+        Ok(())
+    }
+}
+
+macro_rules! tuple_scalar_args {
+    ($($name:ident)+) => {
+        impl<$($name: ScalarArg),+> ScalarArgs for ($($name,)+) {
+            const WORDS: usize = 0 $(+ <$name as ScalarArg>::WORDS)+;
+
+            // The last value advances `start` past the end.
+            #[allow(non_snake_case, unused_assignments)]
+            fn encode(self, words: &mut [usize]) {
+                let ($($name,)+) = self;
+                let mut start = 0;
+                $(
+                    let end = start + <$name as ScalarArg>::WORDS;
+                    ScalarArg::encode($name, &mut words[start..end]);
+                    start = end;
+                )+
+            }
+
+            #[allow(unused_assignments)]
+            fn decode(words: &[usize]) -> Result<Self, Error> {
+                let mut start = 0;
+                Ok(($(
+                    {
+                        let end = start + <$name as ScalarArg>::WORDS;
+                        let value = <$name as ScalarArg>::decode(&words[start..end])?;
+                        start = end;
+                        value
+                    },
+                )+))
+            }
+        }
+    };
+}
+
+tuple_scalar_args!(A);
+tuple_scalar_args!(A B);
+tuple_scalar_args!(A B C);
+tuple_scalar_args!(A B C D);
+tuple_scalar_args!(A B C D E);
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    struct Mix;
+    impl ScalarOpcode for Mix {
+        const OPCODE: usize = 4;
+        type Args = (u64, i32, bool);
+        type Reply = (f64, char, i8);
+    }
+
+    struct Notify;
+    impl ScalarOpcode for Notify {
+        const OPCODE: usize = 5;
+        type Args = u16;
+        type Reply = ();
+    }
+
+    #[test]
+    fn typed_scalars() {
+        assert_eq!(encode_args((u64::MAX - 1, -2i32, true)).len(), 4);
+        assert_eq!(
+            encode_args((0x1_0000_0002u64, -1i32)),
+            [2, 1, 0xffff_ffff, 0]
+        );
+        assert!(matches!(
+            decode_args::<(bool, u8)>([2, 0, 0, 0]),
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            decode_args::<u8>([256, 0, 0, 0]),
+            Err(Error::InvalidArgument)
+        ));
+
+        let sid = create_server_with_address(address("mock-typed")).unwrap();
+        let server = thread::spawn(move || {
+            let envelope = receive_message(sid).unwrap();
+            assert_eq!(envelope.typed_args::<Notify>().unwrap(), 513);
+            assert!(matches!(
+                envelope.typed_args::<Mix>(),
+                Err(Error::InvalidArgument)
+            ));
+            let envelope = receive_message(sid).unwrap();
+            let (value, delta, negate) = envelope.typed_args::<Mix>().unwrap();
+            let value = value as f64 + delta as f64;
+            let reply = (if negate { -value } else { value }, 'é', -3);
+            envelope.return_typed::<Mix>(reply).unwrap();
+        });
+        let cid = connect(address("mock-typed")).unwrap();
+        typed_scalar::<Notify>(cid, 513).unwrap();
+        assert_eq!(
+            typed_blocking_scalar::<Mix>(cid, (1 << 40, -1, true)).unwrap(),
+            (-((1u64 << 40) as f64 - 1.0), 'é', -3)
+        );
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+}
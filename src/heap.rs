@@ -0,0 +1,326 @@
+//! A global allocator for programs that use only this crate.
+//!
+//! With the `global_allocator` feature, [Heap] is installed as the
+//! `#[global_allocator]`, so `alloc` types such as `Vec` and `Box` work without
+//! any further setup. On the host, with the `mock` or `hosted` feature, the
+//! standard library's allocator stays in place and [Heap] may be used
+//! directly.
+//!
+//! Memory is mapped from the kernel with `MapMemory`:
+//!
+//! * Allocations of up to 2048 bytes are rounded up to a power of two and
+//!   carved out of whole pages. Freed blocks are kept for later allocations
+//!   of the same size, and their pages are never returned to the kernel, even
+//!   once every block in a page is free.
+//! * Larger allocations are given pages of their own, which are returned to
+//!   the kernel as soon as they are freed.
+//!
+//! The total never exceeds `Limits::HeapMaximum`, which is read once, when the
+//! heap first maps memory. When memory runs out, the hook set with
+//! [set_oom_hook] is called before the allocation fails.
+//!
+//! `Limits::HeapSize` is not changed by this allocator. It describes the
+//! kernel's own heap region, while this allocator maps separate ranges; use
+//! [Heap::mapped] to see how much memory it holds.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::{Error, Limits, MemoryFlags, PAGE_SIZE, Syscall, SyscallResult, do_yield, syscall};
+
+/// The size of the smallest block, as a power of two.
+const MIN_SHIFT: u32 = 4;
+
+/// The number of block sizes, from 16 to 2048 bytes.
+const CLASSES: usize = 8;
+
+/// The largest allocation that is carved out of a shared page.
+const MAX_SMALL: usize = 1 << (MIN_SHIFT as usize + CLASSES - 1);
+
+/// Called with the layout that could not be allocated and the reason.
+pub type OomHook = fn(Layout, Error);
+
+static OOM_HOOK: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Sets a function to be called whenever an allocation fails, for example to
+/// log the failure or release caches. The allocation still fails once it
+/// returns, and Rust's allocation error handler then runs as usual.
+pub fn set_oom_hook(hook: OomHook) {
+    OOM_HOOK.store(hook as *mut (), Ordering::Release);
+}
+
+fn report_oom(layout: Layout, error: Error) {
+    let hook = OOM_HOOK.load(Ordering::Acquire);
+    if !hook.is_null() {
+        let hook: OomHook = unsafe { core::mem::transmute::<*mut (), OomHook>(hook) };
+        hook(layout, error);
+    }
+}
+
+/// A freed block, which holds the next one in its list.
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct State {
+    free: [*mut FreeBlock; CLASSES],
+    /// The number of bytes mapped from the kernel.
+    mapped: usize,
+    /// `Limits::HeapMaximum`, once it has been read.
+    maximum: Option<usize>,
+}
+
+/// An allocator that maps its memory from the kernel. See the
+/// [module documentation](self) for how memory is managed.
+pub struct Heap {
+    locked: AtomicBool,
+    state: UnsafeCell<State>,
+}
+
+// Safety: `state` is only accessed while `locked` is held.
+unsafe impl Sync for Heap {}
+
+/// Returns the block size used for `layout`, as an index into `State::free`,
+/// or `None` if it is given pages of its own.
+fn class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_SHIFT);
+    if size > MAX_SMALL {
+        return None;
+    }
+    Some((size.next_power_of_two().trailing_zeros() - MIN_SHIFT) as usize)
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (MIN_SHIFT as usize + class)
+}
+
+fn page_round(size: usize) -> Option<usize> {
+    Some(size.checked_next_multiple_of(PAGE_SIZE)?.max(PAGE_SIZE))
+}
+
+impl Heap {
+    /// Creates an allocator that has not yet mapped any memory.
+    pub const fn new() -> Self {
+        Heap {
+            locked: AtomicBool::new(false),
+            state: UnsafeCell::new(State {
+                free: [ptr::null_mut(); CLASSES],
+                mapped: 0,
+                maximum: None,
+            }),
+        }
+    }
+
+    /// Returns the number of bytes currently mapped from the kernel, including
+    /// freed blocks that are kept for reuse.
+    pub fn mapped(&self) -> usize {
+        self.with_state(|state| state.mapped)
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            do_yield();
+        }
+        // The lock is held, so nothing else is using the state.
+        let result = f(unsafe { &mut *self.state.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+
+    /// Maps `size` bytes, which must be a whole number of pages, as long as
+    /// that stays within `Limits::HeapMaximum`.
+    fn map(state: &mut State, size: usize) -> Result<*mut u8, Error> {
+        let maximum = match state.maximum {
+            Some(maximum) => maximum,
+            None => *state.maximum.insert(crate::get_limit(Limits::HeapMaximum)?),
+        };
+        if state
+            .mapped
+            .checked_add(size)
+            .is_none_or(|total| total > maximum)
+        {
+            return Err(Error::OutOfMemory);
+        }
+        let result = unsafe {
+            syscall(
+                Syscall::MapMemory,
+                0,
+                0,
+                size,
+                (MemoryFlags::R | MemoryFlags::W).bits(),
+                0,
+                0,
+                0,
+            )?
+        };
+        if result.0 != SyscallResult::MemoryRange as usize {
+            return Err(Error::InternalError);
+        }
+        state.mapped += result.2;
+        Ok(ptr::with_exposed_provenance_mut(result.1))
+    }
+
+    fn unmap(state: &mut State, ptr: *mut u8, size: usize) {
+        let result = unsafe { syscall(Syscall::UnmapMemory, ptr as usize, size, 0, 0, 0, 0, 0) };
+        if result.is_ok() {
+            state.mapped -= size;
+        }
+    }
+
+    fn alloc_small(state: &mut State, class: usize) -> Result<*mut u8, Error> {
+        if state.free[class].is_null() {
+            // Split a new page into blocks, all of which are free.
+            let page = Self::map(state, PAGE_SIZE)?;
+            let size = class_size(class);
+            for offset in (0..PAGE_SIZE).step_by(size).rev() {
+                let block = unsafe { page.add(offset) }.cast::<FreeBlock>();
+                unsafe {
+                    block.write(FreeBlock {
+                        next: state.free[class],
+                    })
+                };
+                state.free[class] = block;
+            }
+        }
+        let block = state.free[class];
+        state.free[class] = unsafe { (*block).next };
+        Ok(block.cast())
+    }
+
+    fn alloc_large(state: &mut State, layout: Layout) -> Result<*mut u8, Error> {
+        if layout.align() > PAGE_SIZE {
+            return Err(Error::BadAlignment);
+        }
+        Self::map(state, page_round(layout.size()).ok_or(Error::OutOfMemory)?)
+    }
+
+    fn try_alloc(&self, layout: Layout) -> Result<*mut u8, Error> {
+        self.with_state(|state| match class(layout) {
+            Some(class) => Self::alloc_small(state, class),
+            None => Self::alloc_large(state, layout),
+        })
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or_else(|e| {
+            report_oom(layout, e);
+            ptr::null_mut()
+        })
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.alloc(layout) };
+        // Pages from the kernel are already zeroed, but blocks may be reused.
+        if !ptr.is_null() && class(layout).is_some() {
+            unsafe { ptr.write_bytes(0, layout.size()) };
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_state(|state| match class(layout) {
+            Some(class) => {
+                let block = ptr.cast::<FreeBlock>();
+                unsafe {
+                    block.write(FreeBlock {
+                        next: state.free[class],
+                    })
+                };
+                state.free[class] = block;
+            }
+            None => {
+                // `alloc_large` succeeded, so this cannot overflow.
+                Self::unmap(state, ptr, page_round(layout.size()).unwrap());
+            }
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        // Keep the memory if it is already the right size.
+        let same_block = match (class(layout), class(new_layout)) {
+            (Some(old), Some(new)) => old == new,
+            (None, None) => page_round(layout.size()) == page_round(new_size),
+            _ => false,
+        };
+        if same_block {
+            return ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[cfg(all(
+    feature = "global_allocator",
+    not(any(feature = "mock", feature = "hosted"))
+))]
+#[global_allocator]
+static GLOBAL: Heap = Heap::new();
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heap_allocator() {
+        static HEAP: Heap = Heap::new();
+        static OOM: AtomicBool = AtomicBool::new(false);
+
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let a = unsafe { HEAP.alloc(small) };
+        let b = unsafe { HEAP.alloc(small) };
+        assert!(!a.is_null() && !b.is_null() && a != b);
+        assert_eq!(a as usize % 32, 0);
+        assert_eq!(HEAP.mapped(), 4096);
+        unsafe { HEAP.dealloc(a, small) };
+        assert_eq!(unsafe { HEAP.alloc(small) }, a);
+
+        let aligned = Layout::from_size_align(8, 256).unwrap();
+        let c = unsafe { HEAP.alloc_zeroed(aligned) };
+        assert_eq!(c as usize % 256, 0);
+        assert_eq!(unsafe { *c }, 0);
+
+        let large = Layout::from_size_align(10_000, 8).unwrap();
+        let d = unsafe { HEAP.alloc(large) };
+        assert_eq!(d as usize % 4096, 0);
+        assert_eq!(HEAP.mapped(), 2 * 4096 + 12_288);
+        unsafe { d.write_bytes(7, 10_000) };
+        let d = unsafe { HEAP.realloc(d, large, 12_000) };
+        let d = unsafe { HEAP.realloc(d, Layout::from_size_align(12_000, 8).unwrap(), 20_000) };
+        assert_eq!(unsafe { *d.add(9_999) }, 7);
+        assert_eq!(HEAP.mapped(), 2 * 4096 + 20_480);
+        unsafe { HEAP.dealloc(d, Layout::from_size_align(20_000, 8).unwrap()) };
+        assert_eq!(HEAP.mapped(), 2 * 4096);
+
+        set_oom_hook(|layout, error| {
+            assert_eq!(layout.size(), 1 << 30);
+            assert!(matches!(error, Error::OutOfMemory));
+            OOM.store(true, Ordering::Relaxed);
+        });
+        let huge = Layout::from_size_align(1 << 30, 8).unwrap();
+        assert!(unsafe { HEAP.alloc(huge) }.is_null());
+        assert!(OOM.load(Ordering::Relaxed));
+    }
+}
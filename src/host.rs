@@ -0,0 +1,176 @@
+//! Pieces of the kernel that are always handled within the current process when
+//! running on the host, shared by the `mock` and `hosted` backends. Memory is
+//! allocated from the host allocator and threads are OS threads.
+
+extern crate std;
+
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::thread::JoinHandle;
+use std::vec::Vec;
+
+use crate::{Error, SyscallResult};
+
+pub(crate) use crate::PAGE_SIZE;
+
+/// Memory returned by `alloc_pages`, and which of its pages are still mapped.
+struct Mapping {
+    layout: Layout,
+    mapped: Vec<bool>,
+}
+
+static MAPPINGS: LazyLock<Mutex<BTreeMap<usize, Mapping>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+static THREADS: LazyLock<Mutex<HashMap<usize, JoinHandle<usize>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The ID of the first thread to make a syscall, which is taken to be the
+/// process's main thread. Under `hosted`, this is the ID the kernel gave it.
+const MAIN_TID: usize = 1;
+
+#[cfg(feature = "mock")]
+static NEXT_TID: AtomicUsize = AtomicUsize::new(MAIN_TID + 1);
+
+/// IDs for other threads that were not started with `CreateThread`, such as
+/// ones started with `std::thread`. Under `hosted`, the kernel numbers the
+/// threads it creates upwards from the main thread, so these count down from
+/// the top of the range that a 32-bit kernel can represent to stay clear of
+/// them.
+static NEXT_FOREIGN_TID: AtomicUsize = AtomicUsize::new(u32::MAX as usize);
+static MAIN_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+std::thread_local! {
+    static THREAD_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+/// The registers returned by a syscall.
+pub(crate) type Registers = (usize, usize, usize, usize, usize, usize, usize, usize);
+
+/// Builds a successful syscall result of the given `kind`, with `values`
+/// placed in `$a1` onwards.
+pub(crate) fn ok(kind: SyscallResult, values: &[usize]) -> Result<Registers, Error> {
+    let mut r = [0usize; 7];
+    r[..values.len()].copy_from_slice(values);
+    Ok((kind as usize, r[0], r[1], r[2], r[3], r[4], r[5], r[6]))
+}
+
+/// Allocates zeroed, page-aligned memory of at least `len` bytes, returning
+/// its address and actual length.
+pub(crate) fn alloc_pages(len: usize) -> Result<(usize, usize), Error> {
+    let size = len.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+    let layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| Error::OutOfMemory)?;
+    let ptr = unsafe { std::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(Error::OutOfMemory);
+    }
+    let addr = ptr.expose_provenance();
+    MAPPINGS.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        addr,
+        Mapping {
+            layout,
+            mapped: std::vec![true; size / PAGE_SIZE],
+        },
+    );
+    Ok((addr, size))
+}
+
+/// Returns the start of the mapping that holds the pages from `addr` to
+/// `addr + len`, and the index of the first of those pages within it, if
+/// every one of those pages is still mapped.
+fn find_pages(
+    mappings: &BTreeMap<usize, Mapping>,
+    addr: usize,
+    len: usize,
+) -> Option<(usize, usize)> {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return None;
+    }
+    let (&base, mapping) = mappings.range(..=addr).next_back()?;
+    let first = (addr - base) / PAGE_SIZE;
+    let pages = len.div_ceil(PAGE_SIZE).max(1);
+    let range = mapping.mapped.get(first..first.checked_add(pages)?)?;
+    range.iter().all(|&mapped| mapped).then_some((base, first))
+}
+
+/// Unmaps `len` bytes of memory that was returned by `alloc_pages`, starting
+/// at `addr`. As with the kernel, part of a mapping may be unmapped, and the
+/// memory is only freed once every page of it has been.
+pub(crate) fn free_pages(addr: usize, len: usize) -> Result<(), Error> {
+    let mut mappings = MAPPINGS.lock().unwrap_or_else(|e| e.into_inner());
+    let (base, first) = find_pages(&mappings, addr, len).ok_or(Error::BadAddress)?;
+    let mapping = mappings.get_mut(&base).unwrap();
+    let pages = len.div_ceil(PAGE_SIZE).max(1);
+    mapping.mapped[first..first + pages].fill(false);
+    if mapping.mapped.iter().any(|&mapped| mapped) {
+        return Ok(());
+    }
+    let layout = mappings.remove(&base).unwrap().layout;
+    unsafe { std::alloc::dealloc(std::ptr::with_exposed_provenance_mut(base), layout) };
+    Ok(())
+}
+
+/// Returns `true` if every page from `addr` to `addr + len` is part of memory
+/// returned by `alloc_pages`, and has not been unmapped.
+///
+/// Only such memory may be moved to a server. Anything else came from an
+/// allocator whose layout is unknown here, so it could not be freed on the
+/// caller's behalf.
+pub(crate) fn is_mapped(addr: usize, len: usize) -> bool {
+    let mappings = MAPPINGS.lock().unwrap_or_else(|e| e.into_inner());
+    find_pages(&mappings, addr, len).is_some()
+}
+
+/// Returns the ID of the current thread, assigning one on first use if it was
+/// not started with `CreateThread`.
+pub(crate) fn current_thread_id() -> usize {
+    THREAD_ID.with(|tid| {
+        if tid.get() == 0 {
+            tid.set(if MAIN_CLAIMED.swap(true, Ordering::Relaxed) {
+                NEXT_FOREIGN_TID.fetch_sub(1, Ordering::Relaxed)
+            } else {
+                MAIN_TID
+            });
+        }
+        tid.get()
+    })
+}
+
+/// Reserves a new thread ID for a thread started with `CreateThread`, when
+/// the kernel is simulated in this process.
+#[cfg(feature = "mock")]
+pub(crate) fn next_thread_id() -> usize {
+    NEXT_TID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Starts an OS thread with the ID `tid` that calls `entry` with `args`. The
+/// entry point is called as an `extern "C" fn(usize, usize, usize, usize) -> usize`,
+/// and its return value is the result of `join_thread`.
+pub(crate) fn spawn_thread(tid: usize, entry: usize, args: [usize; 4]) -> Result<(), Error> {
+    let entry: extern "C" fn(usize, usize, usize, usize) -> usize =
+        unsafe { core::mem::transmute(core::ptr::with_exposed_provenance::<u8>(entry)) };
+    let handle = std::thread::Builder::new()
+        .spawn(move || {
+            THREAD_ID.with(|id| id.set(tid));
+            entry(args[0], args[1], args[2], args[3])
+        })
+        .map_err(|_| Error::ThreadNotAvailable)?;
+    THREADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(tid, handle);
+    Ok(())
+}
+
+/// Waits for a thread started with `spawn_thread` to exit.
+pub(crate) fn join_thread(tid: usize) -> Result<usize, Error> {
+    let handle = THREADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&tid)
+        .ok_or(Error::ThreadNotAvailable)?;
+    handle.join().map_err(|_| Error::ProcessTerminated)
+}
@@ -0,0 +1,619 @@
+//! Client transport for Xous "hosted mode", used in place of `ecall` when the
+//! `hosted` feature is enabled. Each syscall is forwarded over a socket to a
+//! kernel running on the host, allowing real services to run as ordinary
+//! processes.
+//!
+//! The kernel is located using the `XOUS_SERVER` environment variable, which is
+//! either a TCP address such as `localhost:1238` (the default) or a Unix socket
+//! path prefixed with `unix:`.
+//!
+//! # Wire format
+//!
+//! This follows the client side of hosted mode in xous-core, found in
+//! `xous-rs/src/arch/hosted/mod.rs`. All words are sent as little-endian `u64`
+//! values.
+//!
+//! On connecting, the process sends its 16-byte key, taken from the
+//! `XOUS_PROCESS_KEY` environment variable as 32 hex digits (or all zeroes if
+//! unset). The kernel replies with a single byte containing the process ID.
+//!
+//! Every syscall is then sent as nine words: the thread ID and the eight
+//! argument registers `$a0..=$a7`. The kernel answers with the thread ID and the
+//! eight result registers. Replies may arrive in any order, and are matched to
+//! the waiting thread by its ID.
+//!
+//! Memory that crosses the process boundary follows the registers that describe
+//! it, with its length taken from those registers:
+//!
+//! * `SendMessage` and `TrySendMessage` with `Lend`, `LendMut` or `Move` are
+//!   followed by the buffer, and a `MemoryReturned` reply to `Lend` or `LendMut`
+//!   is followed by the buffer's new contents.
+//! * `ReturnMemory` is followed by the buffer being returned, which is then freed.
+//! * A `Message` result for a memory message is followed by the buffer, which is
+//!   copied into newly-allocated pages in this process.
+//!
+//! The kernel allocates thread IDs, so `CreateThread` is forwarded to it before
+//! the thread is started here. The first thread of the process is thread 1.
+//! Threads that were started some other way, such as with `std::thread`, are
+//! numbered down from `u32::MAX` so that they never share an ID with a
+//! thread the kernel created.
+//!
+//! If reading from the socket fails, the transport is broken for good: every
+//! thread that is waiting for a reply, and every later syscall, fails with
+//! `Error::NetworkError`.
+//! Memory mapping is handled within this process, and is never sent to the
+//! kernel.
+
+extern crate std;
+
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Condvar, LazyLock, Mutex};
+use std::vec::Vec;
+
+use crate::host::{self, Registers, ok};
+use crate::{Error, InvokeType, Syscall, SyscallResult};
+
+const DEFAULT_SERVER: &str = "localhost:1238";
+
+struct Frame {
+    registers: [usize; 8],
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Mailbox {
+    /// For each thread that has lent memory, the number of bytes that follow a
+    /// `MemoryReturned` reply.
+    lent: HashMap<usize, usize>,
+    replies: HashMap<usize, Frame>,
+    /// Set once reading from the socket has failed. The stream can no longer
+    /// be trusted to start on a frame boundary, so every reply that has not
+    /// yet arrived is lost.
+    broken: bool,
+}
+
+struct Transport {
+    writer: Mutex<Box<dyn Write + Send>>,
+    reader: Mutex<Box<dyn Read + Send>>,
+    mailbox: Mutex<Mailbox>,
+    arrived: Condvar,
+}
+
+static TRANSPORT: LazyLock<Result<Transport, Error>> = LazyLock::new(Transport::connect);
+
+impl Transport {
+    fn connect() -> Result<Transport, Error> {
+        let server = std::env::var("XOUS_SERVER").unwrap_or_else(|_| DEFAULT_SERVER.into());
+        let key = match std::env::var("XOUS_PROCESS_KEY") {
+            Ok(hex) => parse_key(&hex)?,
+            Err(_) => [0u8; 16],
+        };
+
+        let (mut writer, mut reader): (Box<dyn Write + Send>, Box<dyn Read + Send>) =
+            if let Some(path) = server.strip_prefix("unix:") {
+                #[cfg(unix)]
+                {
+                    let stream = std::os::unix::net::UnixStream::connect(path)
+                        .map_err(|_| Error::NetworkError)?;
+                    let reader = stream.try_clone().map_err(|_| Error::NetworkError)?;
+                    (Box::new(stream), Box::new(reader))
+                }
+                #[cfg(not(unix))]
+                {
+                    let _ = path;
+                    return Err(Error::NetworkError);
+                }
+            } else {
+                let stream =
+                    std::net::TcpStream::connect(&server).map_err(|_| Error::NetworkError)?;
+                let _ = stream.set_nodelay(true);
+                let reader = stream.try_clone().map_err(|_| Error::NetworkError)?;
+                (Box::new(stream), Box::new(reader))
+            };
+
+        writer.write_all(&key).map_err(|_| Error::NetworkError)?;
+        let mut pid = [0u8; 1];
+        reader
+            .read_exact(&mut pid)
+            .map_err(|_| Error::NetworkError)?;
+
+        Ok(Transport {
+            writer: Mutex::new(writer),
+            reader: Mutex::new(reader),
+            mailbox: Mutex::new(Mailbox::default()),
+            arrived: Condvar::new(),
+        })
+    }
+
+    /// Sends a syscall for `tid`. `lent` is the length of a buffer that is
+    /// being lent, which the kernel sends back along with the reply.
+    fn send(
+        &self,
+        tid: usize,
+        registers: [usize; 8],
+        payload: &[u8],
+        lent: Option<usize>,
+    ) -> Result<(), Error> {
+        if let Some(len) = lent {
+            self.mailbox
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .lent
+                .insert(tid, len);
+        }
+
+        let mut packet = Vec::with_capacity(9 * 8 + payload.len());
+        packet.extend_from_slice(&(tid as u64).to_le_bytes());
+        for register in registers {
+            packet.extend_from_slice(&(register as u64).to_le_bytes());
+        }
+        packet.extend_from_slice(payload);
+
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer
+            .write_all(&packet)
+            .and_then(|_| writer.flush())
+            .map_err(|_| Error::NetworkError)
+    }
+
+    /// Waits for the reply addressed to `tid`. Whichever thread is waiting takes
+    /// a turn reading from the socket, and hands off replies meant for others.
+    fn receive(&self, tid: usize) -> Result<Frame, Error> {
+        let mut mailbox = self.mailbox.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(frame) = mailbox.replies.remove(&tid) {
+                mailbox.lent.remove(&tid);
+                return Ok(frame);
+            }
+            if mailbox.broken {
+                mailbox.lent.remove(&tid);
+                return Err(Error::NetworkError);
+            }
+            let Ok(mut reader) = self.reader.try_lock() else {
+                mailbox = self
+                    .arrived
+                    .wait(mailbox)
+                    .unwrap_or_else(|e| e.into_inner());
+                continue;
+            };
+            drop(mailbox);
+            let frame = self.read_frame(&mut *reader);
+            drop(reader);
+            mailbox = self.mailbox.lock().unwrap_or_else(|e| e.into_inner());
+            match frame {
+                Ok((sender, frame)) => {
+                    mailbox.replies.insert(sender, frame);
+                }
+                // Wake the other waiting threads so that they fail as well,
+                // rather than waiting for a turn that never comes.
+                Err(_) => mailbox.broken = true,
+            }
+            self.arrived.notify_all();
+        }
+    }
+
+    fn read_frame(&self, reader: &mut dyn Read) -> Result<(usize, Frame), Error> {
+        let mut words = [0u8; 9 * 8];
+        reader
+            .read_exact(&mut words)
+            .map_err(|_| Error::NetworkError)?;
+        let mut values = words
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()) as usize);
+        let tid = values.next().unwrap();
+        let mut registers = [0usize; 8];
+        for register in registers.iter_mut() {
+            *register = values.next().unwrap();
+        }
+
+        let len = if registers[0] == SyscallResult::Message as usize && is_memory(registers[2]) {
+            registers[5]
+        } else if registers[0] == SyscallResult::MemoryReturned as usize {
+            self.mailbox
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .lent
+                .get(&tid)
+                .copied()
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let mut payload = std::vec![0u8; len];
+        reader
+            .read_exact(&mut payload)
+            .map_err(|_| Error::NetworkError)?;
+        Ok((tid, Frame { registers, payload }))
+    }
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 16], Error> {
+    let mut key = [0u8; 16];
+    if hex.len() != 32 {
+        return Err(Error::InvalidArgument);
+    }
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).map_err(|_| Error::InvalidArgument)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| Error::InvalidArgument)?;
+    }
+    Ok(key)
+}
+
+fn is_memory(invoke_type: usize) -> bool {
+    invoke_type == InvokeType::Lend as usize
+        || invoke_type == InvokeType::LendMut as usize
+        || invoke_type == InvokeType::Move as usize
+}
+
+/// Entry point called by `raw_syscall` in place of `ecall`.
+pub(crate) unsafe fn raw_syscall(a: [usize; 8]) -> Registers {
+    match unsafe { dispatch(a) } {
+        Ok(result) => result,
+        Err(e) => (SyscallResult::Error as usize, e as usize, 0, 0, 0, 0, 0, 0),
+    }
+}
+
+unsafe fn dispatch(a: [usize; 8]) -> Result<Registers, Error> {
+    // Calls that only concern this process are never forwarded.
+    match a[0] {
+        c if c == Syscall::MapMemory as usize => {
+            if a[1] != 0 || a[2] != 0 {
+                return Err(Error::BadAddress);
+            }
+            let (addr, len) = host::alloc_pages(a[3])?;
+            return ok(SyscallResult::MemoryRange, &[addr, len]);
+        }
+        c if c == Syscall::UnmapMemory as usize => {
+            host::free_pages(a[1], a[2])?;
+            return ok(SyscallResult::Ok, &[]);
+        }
+        c if c == Syscall::UpdateMemoryFlags as usize => {
+            if !host::is_mapped(a[1], a[2]) {
+                return Err(Error::BadAddress);
+            }
+            return ok(SyscallResult::Ok, &[]);
+        }
+        c if c == Syscall::Yield as usize => {
+            std::thread::yield_now();
+            return ok(SyscallResult::Ok, &[]);
+        }
+        c if c == Syscall::JoinThread as usize => {
+            return ok(SyscallResult::Scalar1, &[host::join_thread(a[1])?]);
+        }
+        c if c == Syscall::GetThreadId as usize => {
+            return ok(SyscallResult::ThreadId, &[host::current_thread_id()]);
+        }
+        _ => {}
+    }
+
+    let transport = TRANSPORT.as_ref().map_err(|e| *e)?;
+    let tid = host::current_thread_id();
+
+    let is_send = a[0] == Syscall::SendMessage as usize || a[0] == Syscall::TrySendMessage as usize;
+    let (buf, len) = if is_send && is_memory(a[2]) {
+        (a[4], a[5])
+    } else if a[0] == Syscall::ReturnMemory as usize {
+        (a[2], a[3])
+    } else {
+        (0, 0)
+    };
+    if is_send && a[2] == InvokeType::Move as usize && len != 0 && !host::is_mapped(buf, len) {
+        return Err(Error::BadAddress);
+    }
+    let payload: &[u8] = if len == 0 {
+        &[]
+    } else {
+        unsafe { core::slice::from_raw_parts(core::ptr::with_exposed_provenance(buf), len) }
+    };
+    let is_lend =
+        is_send && (a[2] == InvokeType::Lend as usize || a[2] == InvokeType::LendMut as usize);
+    transport.send(tid, a, payload, is_lend.then_some(len))?;
+
+    if a[0] == Syscall::TerminateProcess as usize {
+        std::process::exit(a[1] as i32);
+    }
+    if a[0] == Syscall::ReturnMemory as usize && len != 0 {
+        // The memory now belongs to the sender again.
+        host::free_pages(buf, len)?;
+    }
+
+    let Frame {
+        mut registers,
+        payload,
+    } = transport.receive(tid)?;
+
+    if is_send && a[2] == InvokeType::Move as usize && len != 0 {
+        // The caller has given up its buffer, even if the call failed.
+        host::free_pages(buf, len)?;
+    }
+
+    if registers[0] == SyscallResult::MemoryReturned as usize
+        && is_send
+        && a[2] == InvokeType::LendMut as usize
+    {
+        let dest = unsafe {
+            core::slice::from_raw_parts_mut(core::ptr::with_exposed_provenance_mut(buf), len)
+        };
+        let count = dest.len().min(payload.len());
+        dest[..count].copy_from_slice(&payload[..count]);
+    }
+
+    if registers[0] == SyscallResult::Message as usize && is_memory(registers[2]) {
+        registers[4] = if registers[5] == 0 {
+            0
+        } else {
+            let (local, _) = host::alloc_pages(registers[5])?;
+            let dest = unsafe {
+                core::slice::from_raw_parts_mut(
+                    core::ptr::with_exposed_provenance_mut(local),
+                    registers[5],
+                )
+            };
+            let count = dest.len().min(payload.len());
+            dest[..count].copy_from_slice(&payload[..count]);
+            local
+        };
+    }
+
+    if a[0] == Syscall::CreateThread as usize && registers[0] == SyscallResult::ThreadId as usize {
+        // The kernel has assigned the new thread's ID, so start it here.
+        host::spawn_thread(registers[1], a[1], [a[4], a[5], a[6], a[7]])?;
+    }
+
+    Ok((
+        registers[0],
+        registers[1],
+        registers[2],
+        registers[3],
+        registers[4],
+        registers[5],
+        registers[6],
+        registers[7],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::string::ToString;
+    use std::sync::{Arc, LazyLock, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use std::vec::Vec;
+
+    use crate::*;
+
+    /// Opcode that the fake kernel answers after a delay, so that replies
+    /// arrive out of order.
+    const SLOW: usize = 1;
+
+    fn read_words(stream: &mut TcpStream) -> Option<[usize; 9]> {
+        let mut bytes = [0u8; 9 * 8];
+        stream.read_exact(&mut bytes).ok()?;
+        let mut words = [0usize; 9];
+        for (word, src) in words.iter_mut().zip(bytes.chunks_exact(8)) {
+            *word = u64::from_le_bytes(src.try_into().unwrap()) as usize;
+        }
+        Some(words)
+    }
+
+    fn reply(writer: &Mutex<TcpStream>, tid: usize, registers: [usize; 8], payload: &[u8]) {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&(tid as u64).to_le_bytes());
+        for register in registers {
+            packet.extend_from_slice(&(register as u64).to_le_bytes());
+        }
+        packet.extend_from_slice(payload);
+        writer.lock().unwrap().write_all(&packet).unwrap();
+    }
+
+    /// A stand-in for the hosted kernel that answers the handful of calls
+    /// used by these tests.
+    fn serve(mut stream: TcpStream) {
+        let mut key = [0u8; 16];
+        stream.read_exact(&mut key).unwrap();
+        stream.write_all(&[2]).unwrap();
+        let writer = Arc::new(Mutex::new(stream.try_clone().unwrap()));
+
+        while let Some([tid, call, a1, a2, a3, a4, a5, a6, a7]) = read_words(&mut stream) {
+            let is_send = call == Syscall::SendMessage as usize;
+            let len = if is_send
+                && a2 != InvokeType::Scalar as usize
+                && a2 != InvokeType::BlockingScalar as usize
+            {
+                a5
+            } else if call == Syscall::ReturnMemory as usize {
+                a3
+            } else {
+                0
+            };
+            let mut payload = std::vec![0u8; len];
+            stream.read_exact(&mut payload).unwrap();
+
+            let writer = writer.clone();
+            match call {
+                c if c == Syscall::Connect as usize => reply(
+                    &writer,
+                    tid,
+                    [SyscallResult::ConnectionId as usize, 5, 0, 0, 0, 0, 0, 0],
+                    &[],
+                ),
+                c if c == Syscall::CreateThread as usize => reply(
+                    &writer,
+                    tid,
+                    [SyscallResult::ThreadId as usize, 77, 0, 0, 0, 0, 0, 0],
+                    &[],
+                ),
+                c if c == Syscall::ReceiveMessage as usize => {
+                    let mut buf = std::vec![0u8; 4096];
+                    buf[..5].copy_from_slice(b"hello");
+                    let registers = [
+                        SyscallResult::Message as usize,
+                        9,
+                        InvokeType::LendMut as usize,
+                        4,
+                        0x1000,
+                        4096,
+                        0,
+                        5,
+                    ];
+                    reply(&writer, tid, registers, &buf);
+                }
+                c if c == Syscall::ReturnMemory as usize => {
+                    assert_eq!(a1, 9);
+                    assert_eq!(&payload[..5], b"HELLO");
+                    reply(
+                        &writer,
+                        tid,
+                        [SyscallResult::Ok as usize, 0, 0, 0, 0, 0, 0, 0],
+                        &[],
+                    );
+                }
+                _ if is_send && a2 == InvokeType::BlockingScalar as usize => {
+                    thread::spawn(move || {
+                        if a3 == SLOW {
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                        let registers =
+                            [SyscallResult::Scalar1 as usize, a4 + a5, 0, 0, 0, 0, 0, 0];
+                        reply(&writer, tid, registers, &[]);
+                    });
+                }
+                _ if is_send && a2 == InvokeType::LendMut as usize => {
+                    payload.make_ascii_uppercase();
+                    let registers = [
+                        SyscallResult::MemoryReturned as usize,
+                        a6,
+                        a7,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                    ];
+                    reply(&writer, tid, registers, &payload);
+                }
+                _ => reply(
+                    &writer,
+                    tid,
+                    [
+                        SyscallResult::Error as usize,
+                        Error::UnhandledSyscall as usize,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                    ],
+                    &[],
+                ),
+            }
+        }
+    }
+
+    /// Starts the fake kernel and points the transport at it. Every test
+    /// shares the one connection.
+    fn kernel() {
+        static KERNEL: LazyLock<()> = LazyLock::new(|| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            // The transport reads this when it first connects, which is after
+            // this has run.
+            unsafe { std::env::set_var("XOUS_SERVER", address.to_string()) };
+            thread::spawn(move || serve(listener.accept().unwrap().0));
+        });
+        LazyLock::force(&KERNEL);
+    }
+
+    #[test]
+    fn scalars_are_matched_to_their_threads() {
+        kernel();
+        let cid = connect(ServerAddress::try_from("hosted-test").unwrap()).unwrap();
+        let slow = thread::spawn(move || blocking_scalar(cid, [SLOW, 1, 2, 0, 0]).unwrap()[0]);
+        thread::sleep(Duration::from_millis(20));
+        // This is answered while the slow call is still waiting.
+        assert_eq!(blocking_scalar(cid, [0, 20, 22, 0, 0]).unwrap()[0], 42);
+        assert_eq!(slow.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn lent_memory_is_written_back() {
+        kernel();
+        let cid = connect(ServerAddress::try_from("hosted-test").unwrap()).unwrap();
+        let mut buf = PageBuf::<1>::from_slice(b"shout").unwrap();
+        assert_eq!(lend_pages_mut(cid, 0, &mut buf, 3, 4).unwrap(), (3, 4));
+        assert_eq!(&buf[..5], b"SHOUT");
+    }
+
+    #[test]
+    fn received_memory_is_copied_in_and_returned() {
+        kernel();
+        let mut envelope = receive_message(ServerId::from([1, 2, 3, 4])).unwrap();
+        assert_eq!(envelope.opcode(), 4);
+        assert_eq!(envelope.invoke_type(), InvokeType::LendMut);
+        let buf = envelope.buf_mut().unwrap();
+        assert_eq!(buf.len(), 4096);
+        buf[..5].make_ascii_uppercase();
+        envelope.return_memory(0, 5).unwrap();
+    }
+
+    #[test]
+    fn waiting_threads_fail_when_the_stream_breaks() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                thread::sleep(Duration::from_millis(20));
+                Err(std::io::ErrorKind::ConnectionReset.into())
+            }
+        }
+
+        let transport = Arc::new(super::Transport {
+            writer: Mutex::new(std::boxed::Box::new(std::io::sink())),
+            reader: Mutex::new(std::boxed::Box::new(Broken)),
+            mailbox: Mutex::new(super::Mailbox::default()),
+            arrived: std::sync::Condvar::new(),
+        });
+        let waiters: Vec<_> = (1..=3)
+            .map(|tid| {
+                let transport = transport.clone();
+                thread::spawn(move || transport.receive(tid).err())
+            })
+            .collect();
+        for waiter in waiters {
+            assert!(matches!(waiter.join().unwrap(), Some(Error::NetworkError)));
+        }
+    }
+
+    #[test]
+    fn other_threads_get_ids_the_kernel_does_not_assign() {
+        let main: usize = thread_id().unwrap().into();
+        let other: usize = thread::spawn(|| thread_id().unwrap())
+            .join()
+            .unwrap()
+            .into();
+        assert_ne!(main, other);
+        assert!(main == 1 || main > u16::MAX as usize);
+        assert!(other > u16::MAX as usize);
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    extern "C" fn thread_main(a: usize, b: usize, _: usize, _: usize) -> usize {
+        a * b
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    #[test]
+    fn threads_are_numbered_by_the_kernel() {
+        kernel();
+        let stack = std::vec![0u8; 4096].into_boxed_slice();
+        let tid = create_thread(thread_main as *mut usize, stack, 6, 7, 0, 0).unwrap();
+        assert_eq!(Into::<usize>::into(tid), 77);
+        assert_eq!(join_thread(tid).unwrap(), 42);
+    }
+}
@@ -2,7 +2,31 @@
 mod definitions;
 pub use definitions::*;
 
+#[cfg(feature = "global_allocator")]
+pub mod heap;
 pub mod ns;
+mod retry;
+pub use retry::*;
+mod send;
+pub use send::*;
+pub mod server;
+pub mod sync;
+#[cfg(feature = "alloc")]
+pub mod thread;
+mod threads;
+pub use threads::*;
+pub mod ticktimer;
+mod validate;
+
+#[cfg(any(feature = "mock", feature = "hosted"))]
+mod host;
+#[cfg(feature = "hosted")]
+mod hosted;
+#[cfg(feature = "mock")]
+mod mock;
+
+#[cfg(all(feature = "mock", feature = "hosted"))]
+compile_error!("the `mock` and `hosted` features cannot be enabled together");
 
 #[cfg(feature = "unstable_mem")]
 mod unstable;
@@ -13,6 +37,10 @@ pub use unstable::*;
 ///
 /// Safety: The safety of the function depends on the syscall
 /// passed in `a0`.
+///
+/// When the `mock` feature is enabled, the syscall is handled by an
+/// in-process simulation of the kernel instead. When the `hosted` feature
+/// is enabled, the syscall is forwarded to a kernel running on the host.
 #[inline]
 pub unsafe fn raw_syscall(
     mut a0: usize,
@@ -24,6 +52,17 @@ pub unsafe fn raw_syscall(
     mut a6: usize,
     mut a7: usize,
 ) -> (usize, usize, usize, usize, usize, usize, usize, usize) {
+    #[cfg(feature = "mock")]
+    {
+        (a0, a1, a2, a3, a4, a5, a6, a7) =
+            unsafe { mock::raw_syscall([a0, a1, a2, a3, a4, a5, a6, a7]) };
+    }
+    #[cfg(feature = "hosted")]
+    {
+        (a0, a1, a2, a3, a4, a5, a6, a7) =
+            unsafe { hosted::raw_syscall([a0, a1, a2, a3, a4, a5, a6, a7]) };
+    }
+    #[cfg(not(any(feature = "mock", feature = "hosted")))]
     unsafe {
         core::arch::asm!(
             "ecall",
@@ -72,19 +111,10 @@ pub fn lend_mut(
     arg1: usize,
     arg2: usize,
 ) -> Result<(usize, usize), Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::SendMessage,
-            connection.0 as _,
-            InvokeType::LendMut as _,
-            opcode,
-            data.as_ptr() as usize,
-            data.len(),
-            arg1,
-            arg2,
-        )?
-    };
-    Ok((result.1, result.2))
+    Message::lend_mut(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Block)
 }
 
 /// Attempt to mutably lend the buffer to the server.
@@ -96,19 +126,10 @@ pub fn try_lend_mut(
     arg1: usize,
     arg2: usize,
 ) -> Result<(usize, usize), Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::TrySendMessage,
-            connection.0 as _,
-            InvokeType::LendMut as _,
-            opcode,
-            data.as_ptr() as usize,
-            data.len(),
-            arg1,
-            arg2,
-        )?
-    };
-    Ok((result.1, result.2))
+    Message::lend_mut(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Try)
 }
 
 /// Lend the buffer to the server. Blocks if the mailbox is full.
@@ -119,19 +140,10 @@ pub fn lend(
     arg1: usize,
     arg2: usize,
 ) -> Result<(usize, usize), Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::SendMessage,
-            connection.0 as _,
-            InvokeType::Lend as _,
-            opcode,
-            data.as_ptr() as usize,
-            data.len(),
-            arg1,
-            arg2,
-        )?
-    };
-    Ok((result.1, result.2))
+    Message::lend(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Block)
 }
 
 /// Attempt to lend the slice to the server. Returns an error if
@@ -143,89 +155,114 @@ pub fn try_lend(
     arg1: usize,
     arg2: usize,
 ) -> Result<(usize, usize), Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::TrySendMessage,
-            connection.0 as _,
-            InvokeType::Lend as _,
-            opcode,
-            data.as_ptr() as usize,
-            data.len(),
-            arg1,
-            arg2,
-        )?
-    };
-    Ok((result.1, result.2))
+    Message::lend(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Try)
+}
+
+/// Lend a page-aligned buffer to the server. Blocks if the mailbox is full.
+///
+/// Unlike `lend`, the buffer is guaranteed to meet the kernel's alignment
+/// requirements.
+pub fn lend_pages(
+    connection: Connection,
+    opcode: usize,
+    data: &impl PageAligned,
+    arg1: usize,
+    arg2: usize,
+) -> Result<(usize, usize), Error> {
+    lend(connection, opcode, data.as_bytes(), arg1, arg2)
+}
+
+/// Mutably lend a page-aligned buffer to the server, blocking if the
+/// mailbox is full.
+///
+/// Unlike `lend_mut`, the buffer is guaranteed to meet the kernel's alignment
+/// requirements.
+pub fn lend_pages_mut(
+    connection: Connection,
+    opcode: usize,
+    data: &mut impl PageAligned,
+    arg1: usize,
+    arg2: usize,
+) -> Result<(usize, usize), Error> {
+    lend_mut(connection, opcode, data.as_bytes_mut(), arg1, arg2)
 }
 
 /// Send 5 scalar values to the server, blocking if the mailbox is full.
 pub fn scalar(connection: Connection, args: [usize; 5]) -> Result<(), Error> {
-    unsafe {
-        syscall(
-            Syscall::SendMessage,
-            connection.0 as _,
-            InvokeType::Scalar as _,
-            args[0],
-            args[1],
-            args[2],
-            args[3],
-            args[4],
-        )?
-    };
-    Ok(())
+    Message::scalar()
+        .opcode(args[0])
+        .scalar_args([args[1], args[2], args[3], args[4]])
+        .send(connection, SendMode::Block)
 }
 
 /// Attempt to send 5 scalar values to the server.
 pub fn try_scalar(connection: Connection, args: [usize; 5]) -> Result<(), Error> {
-    unsafe {
-        syscall(
-            Syscall::TrySendMessage,
-            connection.0 as _,
-            InvokeType::Scalar as _,
-            args[0],
-            args[1],
-            args[2],
-            args[3],
-            args[4],
-        )?
-    };
-    Ok(())
+    Message::scalar()
+        .opcode(args[0])
+        .scalar_args([args[1], args[2], args[3], args[4]])
+        .send(connection, SendMode::Try)
 }
 
 /// Send 5 scalar arguments to a server and wait for a response.
 /// If the server mailbox is full, will block until it is available.
 pub fn blocking_scalar(connection: Connection, args: [usize; 5]) -> Result<[usize; 5], Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::SendMessage,
-            connection.0 as _,
-            InvokeType::BlockingScalar as _,
-            args[0],
-            args[1],
-            args[2],
-            args[3],
-            args[4],
-        )?
-    };
-    Ok([result.1, result.2, result.3, result.4, result.5])
+    Message::blocking_scalar()
+        .opcode(args[0])
+        .scalar_args([args[1], args[2], args[3], args[4]])
+        .send(connection, SendMode::Block)
 }
 
 /// Attempt to send 5 scalar arguments to a server. Returns an error
 /// if the server mailbox is full.
 pub fn try_blocking_scalar(connection: Connection, args: [usize; 5]) -> Result<[usize; 5], Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::TrySendMessage,
-            connection.0 as _,
-            InvokeType::BlockingScalar as _,
-            args[0],
-            args[1],
-            args[2],
-            args[3],
-            args[4],
-        )?
-    };
-    Ok([result.1, result.2, result.3, result.4, result.5])
+    Message::blocking_scalar()
+        .opcode(args[0])
+        .scalar_args([args[1], args[2], args[3], args[4]])
+        .send(connection, SendMode::Try)
+}
+
+/// Send the typed arguments of opcode `O` to the server, blocking if the
+/// mailbox is full.
+pub fn typed_scalar<O: ScalarOpcode>(connection: Connection, args: O::Args) -> Result<(), Error> {
+    let [a1, a2, a3, a4] = encode_args(args);
+    scalar(connection, [O::OPCODE, a1, a2, a3, a4])
+}
+
+/// Attempt to send the typed arguments of opcode `O` to the server.
+pub fn try_typed_scalar<O: ScalarOpcode>(
+    connection: Connection,
+    args: O::Args,
+) -> Result<(), Error> {
+    let [a1, a2, a3, a4] = encode_args(args);
+    try_scalar(connection, [O::OPCODE, a1, a2, a3, a4])
+}
+
+/// Send the typed arguments of opcode `O` to a server and wait for its typed
+/// response. If the server mailbox is full, will block until it is available.
+///
+/// Returns `Error::InvalidArgument` if the response is not a valid `O::Reply`.
+pub fn typed_blocking_scalar<O: ScalarOpcode>(
+    connection: Connection,
+    args: O::Args,
+) -> Result<O::Reply, Error> {
+    let [a1, a2, a3, a4] = encode_args(args);
+    decode_reply(blocking_scalar(connection, [O::OPCODE, a1, a2, a3, a4])?)
+}
+
+/// Attempt to send the typed arguments of opcode `O` to a server and wait for
+/// its typed response. Returns an error if the server mailbox is full.
+pub fn try_typed_blocking_scalar<O: ScalarOpcode>(
+    connection: Connection,
+    args: O::Args,
+) -> Result<O::Reply, Error> {
+    let [a1, a2, a3, a4] = encode_args(args);
+    decode_reply(try_blocking_scalar(
+        connection,
+        [O::OPCODE, a1, a2, a3, a4],
+    )?)
 }
 
 /// Connects to a Xous server represented by the specified `address`.
@@ -248,13 +285,31 @@ pub fn connect(address: ServerAddress) -> Result<Connection, Error> {
     Ok(Connection(result.1 as u32))
 }
 
+/// Connects to a Xous server represented by the specified `address`, returning
+/// a connection that is disconnected when dropped.
+///
+/// The current thread will block until the server is available.
+///
+/// # Safety
+///
+/// The kernel returns the same connection ID every time this process connects
+/// to a given server, so dropping the returned value disconnects every other
+/// `Connection` to that server as well. The caller must ensure that no other
+/// part of the program is using a connection to this server, and that none will
+/// be made while the returned value is alive.
+pub unsafe fn connect_owned(address: ServerAddress) -> Result<OwnedConnection, Error> {
+    let connection = connect(address)?;
+    // The caller guarantees that nothing else is using this connection.
+    Ok(unsafe { OwnedConnection::from_raw(connection) })
+}
+
 /// Attempts to connect to a Xous server represented by the specified `address`.
 ///
-/// If the server does not exist then None is returned.
+/// If the server does not exist then None is returned immediately.
 pub fn try_connect(address: ServerAddress) -> Result<Option<Connection>, Error> {
     let result = unsafe {
         syscall(
-            Syscall::Connect,
+            Syscall::TryConnect,
             address.0[0] as usize,
             address.0[1] as usize,
             address.0[2] as usize,
@@ -271,6 +326,22 @@ pub fn try_connect(address: ServerAddress) -> Result<Option<Connection>, Error>
     }
 }
 
+/// Connects to a Xous server represented by the specified `address`, giving up
+/// with `Error::Timeout` if the server does not appear within `timeout`.
+///
+/// The server is polled with `try_connect`, sleeping for an increasing interval
+/// between attempts. Time is measured with the ticktimer server, so if that has
+/// not started yet the server is polled between calls to `do_yield`, and the
+/// timeout only begins to run once the ticktimer is available.
+pub fn connect_timeout(
+    address: ServerAddress,
+    timeout: core::time::Duration,
+) -> Result<Connection, Error> {
+    RetryPolicy::new()
+        .deadline(timeout)
+        .poll(|| try_connect(address))
+}
+
 /// Attempts to disconnect from the specified Xous server.
 ///
 /// Safety: If this connection is in use elsewhere in this program,
@@ -281,6 +352,154 @@ pub unsafe fn disconnect(connection: Connection) -> Result<(), Error> {
     Ok(())
 }
 
+/// Creates a new server with an address chosen by the kernel.
+///
+/// Servers created this way can only be connected to by processes that
+/// are given the address, for example via the name server. The address is
+/// obtained with `ServerAddress::from(server_id)`.
+pub fn create_server() -> Result<ServerId, Error> {
+    let result = unsafe { syscall(Syscall::CreateServer, 0, 0, 0, 0, 0, 0, 0)? };
+    if result.0 != SyscallResult::NewServerId as usize {
+        return Err(Error::InternalError);
+    }
+    Ok(ServerId([
+        result.1 as u32,
+        result.2 as u32,
+        result.3 as u32,
+        result.4 as u32,
+    ]))
+}
+
+/// Creates a new server at the specified `address`, allowing any process
+/// that knows the address to `connect()` to it.
+///
+/// Returns `Error::ServerExists` if a server is already running at that address.
+pub fn create_server_with_address(address: ServerAddress) -> Result<ServerId, Error> {
+    let result = unsafe {
+        syscall(
+            Syscall::CreateServerWithAddress,
+            address.0[0] as usize,
+            address.0[1] as usize,
+            address.0[2] as usize,
+            address.0[3] as usize,
+            0,
+            0,
+            0,
+        )?
+    };
+    if result.0 != SyscallResult::NewServerId as usize {
+        return Err(Error::InternalError);
+    }
+    Ok(ServerId([
+        result.1 as u32,
+        result.2 as u32,
+        result.3 as u32,
+        result.4 as u32,
+    ]))
+}
+
+/// Destroys the given server. Any connections to the server will fail
+/// once it has been destroyed, and its address may be reused.
+pub fn destroy_server(server: ServerId) -> Result<(), Error> {
+    unsafe {
+        syscall(
+            Syscall::DestroyServer,
+            server.0[0] as usize,
+            server.0[1] as usize,
+            server.0[2] as usize,
+            server.0[3] as usize,
+            0,
+            0,
+            0,
+        )?
+    };
+    Ok(())
+}
+
+/// Waits for a message to arrive at the given `server`.
+///
+/// The current thread will block until a message is available. Blocking
+/// messages are answered automatically when the returned envelope is dropped,
+/// unless the response is deferred with [`MessageEnvelope::defer`].
+pub fn receive_message(server: ServerId) -> Result<MessageEnvelope, Error> {
+    let result = unsafe {
+        syscall(
+            Syscall::ReceiveMessage,
+            server.0[0] as usize,
+            server.0[1] as usize,
+            server.0[2] as usize,
+            server.0[3] as usize,
+            0,
+            0,
+            0,
+        )?
+    };
+
+    if result.0 != SyscallResult::Message as usize {
+        return Err(Error::InternalError);
+    }
+    MessageEnvelope::from_registers([
+        result.1, result.2, result.3, result.4, result.5, result.6, result.7,
+    ])
+}
+
+/// Responds to a `BlockingScalar` message with five values. These become
+/// the result of the sender's call to `blocking_scalar`.
+pub fn return_scalar(sender: MessageSender, args: [usize; 5]) -> Result<(), Error> {
+    unsafe {
+        syscall(
+            Syscall::ReturnScalar,
+            sender.0,
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+            args[4],
+            0,
+        )?
+    };
+    Ok(())
+}
+
+/// Responds to a `BlockingScalar` message with a single value. The sender
+/// will see this as the first value returned by `blocking_scalar`.
+pub fn return_scalar1(sender: MessageSender, arg1: usize) -> Result<(), Error> {
+    unsafe { syscall(Syscall::ReturnScalar1, sender.0, arg1, 0, 0, 0, 0, 0)? };
+    Ok(())
+}
+
+/// Responds to a `BlockingScalar` message with two values. The sender
+/// will see these as the first two values returned by `blocking_scalar`.
+pub fn return_scalar2(sender: MessageSender, arg1: usize, arg2: usize) -> Result<(), Error> {
+    unsafe { syscall(Syscall::ReturnScalar2, sender.0, arg1, arg2, 0, 0, 0, 0)? };
+    Ok(())
+}
+
+/// Returns memory that was lent to this server with `lend` or `lend_mut`.
+/// The `offset` and `valid` values become the result of the sender's call.
+///
+/// The memory is unmapped from this process once it has been returned.
+pub fn return_memory(
+    sender: MessageSender,
+    range: MemoryMessage,
+    offset: usize,
+    valid: usize,
+) -> Result<(), Error> {
+    unsafe {
+        syscall(
+            Syscall::ReturnMemory,
+            sender.0,
+            range.addr,
+            range.len,
+            offset,
+            valid,
+            0,
+            0,
+        )?
+    };
+    Ok(())
+}
+
 /// Terminates the current process and returns the specified code to the parent process.
 pub fn exit(exit_code: u32) -> ! {
     let _ = unsafe { syscall(Syscall::TerminateProcess, exit_code as _, 0, 0, 0, 0, 0, 0) };
@@ -328,11 +547,200 @@ pub fn adjust_limit(knob: Limits, current: usize, new: usize) -> Result<usize, E
         )?
     };
 
-    if result.0 == SyscallResult::Scalar2 as usize && result.1 == knob as usize {
+    // Both forms of the result name the knob first, followed by its value.
+    if (result.0 == SyscallResult::Scalar2 as usize || result.0 == SyscallResult::Scalar5 as usize)
+        && result.1 == knob as usize
+    {
         Ok(result.2)
-    } else if result.0 == SyscallResult::Scalar5 as usize && result.1 == knob as usize {
-        Ok(result.1)
     } else {
         Err(Error::InternalError)
     }
 }
+
+fn limit_error(knob: Limits, error: Error) -> LimitError {
+    match error {
+        Error::InvalidLimit => LimitError::Unsupported(knob),
+        e => LimitError::Kernel(e),
+    }
+}
+
+/// Returns the current value of the `knob` limit.
+pub fn get_limit(knob: Limits) -> Result<usize, LimitError> {
+    // A compare-and-set only succeeds if the value is already `0`, in which
+    // case it does not change. Otherwise it returns the current value.
+    adjust_limit(knob, 0, 0).map_err(|e| limit_error(knob, e))
+}
+
+/// Sets the `knob` limit to `new`, whatever its current value, returning the
+/// value it had before. If another thread changes the limit at the same time,
+/// this tries again.
+///
+/// Returns `LimitError::Refused` if the kernel will not accept the new value.
+pub fn set_limit(knob: Limits, new: usize) -> Result<usize, LimitError> {
+    let mut current = get_limit(knob)?;
+    loop {
+        let result = adjust_limit(knob, current, new).map_err(|e| limit_error(knob, e))?;
+        if result == new {
+            return Ok(current);
+        }
+        if result == current {
+            return Err(LimitError::Refused { knob, current });
+        }
+        // The limit was changed in the meantime.
+        current = result;
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn connect_and_try_connect() {
+        assert!(matches!(try_connect(address("mock-missing")), Ok(None)));
+
+        let sid = create_server_with_address(address("mock-connect")).unwrap();
+        assert!(matches!(
+            create_server_with_address(address("mock-connect")),
+            Err(Error::ServerExists)
+        ));
+        let cid: u32 = connect(address("mock-connect")).unwrap().into();
+        let again: u32 = try_connect(address("mock-connect"))
+            .unwrap()
+            .unwrap()
+            .into();
+        assert_eq!(cid, again);
+
+        destroy_server(sid).unwrap();
+        assert!(matches!(try_connect(address("mock-connect")), Ok(None)));
+    }
+
+    #[test]
+    fn connect_to_created_server() {
+        let sid = create_server().unwrap();
+        let server = thread::spawn(move || {
+            let envelope = receive_message(sid).unwrap();
+            let opcode = envelope.opcode();
+            envelope.return_scalar([opcode, 0, 0, 0, 0]).unwrap();
+        });
+        let cid = connect(ServerAddress::from(sid)).unwrap();
+        assert_eq!(blocking_scalar(cid, [12, 0, 0, 0, 0]).unwrap()[0], 12);
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+
+    #[test]
+    fn blocking_scalar_round_trip() {
+        let sid = create_server_with_address(address("mock-scalar")).unwrap();
+        let server = thread::spawn(move || {
+            let envelope = receive_message(sid).unwrap();
+            assert_eq!(envelope.opcode(), 3);
+            assert_eq!(envelope.invoke_type(), InvokeType::BlockingScalar);
+            let [a, b, c, d] = envelope.scalar_args().unwrap();
+            envelope.return_scalar([a + b, c * d, 7, 8, 9]).unwrap();
+        });
+        let cid = connect(address("mock-scalar")).unwrap();
+        assert_eq!(
+            blocking_scalar(cid, [3, 1, 2, 3, 4]).unwrap(),
+            [3, 12, 7, 8, 9]
+        );
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+
+    #[test]
+    fn lend_mut_writes_back() {
+        let sid = create_server_with_address(address("mock-lend-mut")).unwrap();
+        let server = thread::spawn(move || {
+            let mut envelope = receive_message(sid).unwrap();
+            let buf = envelope.buf_mut().unwrap();
+            assert_eq!(&buf[..3], b"abc");
+            buf[..3].copy_from_slice(b"xyz");
+            let valid = envelope.memory().unwrap().valid;
+            envelope.return_memory(1, valid + 1).unwrap();
+        });
+        let cid = connect(address("mock-lend-mut")).unwrap();
+        let mut buf = PageBuf::<1>::from_slice(b"abc").unwrap();
+        assert_eq!(lend_pages_mut(cid, 0, &mut buf, 0, 3).unwrap(), (1, 4));
+        assert_eq!(&buf[..3], b"xyz");
+        server.join().unwrap();
+
+        // Lent memory must be page-aligned.
+        #[cfg(not(feature = "validate"))]
+        assert!(matches!(
+            lend_mut(cid, 0, &mut buf[1..4097 - 1], 0, 0),
+            Err(Error::BadAlignment)
+        ));
+        destroy_server(sid).unwrap();
+    }
+
+    #[test]
+    fn owned_connection_disconnects_on_drop() {
+        let sid = create_server_with_address(address("mock-owned")).unwrap();
+        let owned = unsafe { connect_owned(address("mock-owned")) }.unwrap();
+        let cid = *owned;
+        drop(owned);
+        assert!(matches!(
+            scalar(cid, [0, 0, 0, 0, 0]),
+            Err(Error::ServerNotFound)
+        ));
+
+        let owned = unsafe { connect_owned(address("mock-owned")) }.unwrap();
+        let cid = owned.into_raw();
+        scalar(cid, [0, 0, 0, 0, 0]).unwrap();
+        unsafe { disconnect(cid) }.unwrap();
+        destroy_server(sid).unwrap();
+    }
+
+    #[test]
+    fn connect_timeout_expires() {
+        assert!(matches!(
+            connect_timeout(address("mock-late"), Duration::ZERO),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            connect_timeout(address("mock-late"), Duration::from_millis(20)),
+            Err(Error::Timeout)
+        ));
+
+        let creator = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(20));
+            create_server_with_address(address("mock-late")).unwrap()
+        });
+        connect_timeout(address("mock-late"), Duration::from_secs(5)).unwrap();
+        destroy_server(creator.join().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn process_limits() {
+        let maximum = get_limit(Limits::HeapMaximum).unwrap();
+        assert_eq!(maximum, 64 * 1024 * 1024);
+
+        let size = get_limit(Limits::HeapSize).unwrap();
+        assert_eq!(adjust_limit(Limits::HeapSize, size + 1, 0).unwrap(), size);
+        assert_eq!(set_limit(Limits::HeapSize, 8192).unwrap(), size);
+        assert_eq!(get_limit(Limits::HeapSize).unwrap(), 8192);
+        assert!(matches!(
+            set_limit(Limits::HeapSize, maximum + 1),
+            Err(LimitError::Refused {
+                knob: Limits::HeapSize,
+                current: 8192
+            })
+        ));
+        assert_eq!(set_limit(Limits::HeapSize, size).unwrap(), 8192);
+
+        // Kernels that do not know a limit answer with `InvalidLimit`.
+        let error = super::limit_error(Limits::HeapMaximum, Error::InvalidLimit);
+        assert!(matches!(
+            error,
+            LimitError::Unsupported(Limits::HeapMaximum)
+        ));
+        assert!(matches!(Error::from(error), Error::InvalidLimit));
+    }
+}
@@ -0,0 +1,716 @@
+//! An in-process stand-in for the Xous kernel, used in place of `ecall` when the
+//! `mock` feature is enabled. This allows code built on this crate to run under
+//! `cargo test` on the host.
+//!
+//! The simulation covers a single process:
+//!
+//! * Servers and connections live in a global table. `Connect` blocks until the
+//!   server is created, exactly as it would on hardware.
+//! * Threads created with `CreateThread` are OS threads. The entry point is called
+//!   as an `extern "C" fn(usize, usize, usize, usize) -> usize`, and its return
+//!   value is the result of `JoinThread`. The stack that was passed in is unused.
+//! * Memory that is lent is copied into a fresh page-aligned region for the server,
+//!   and copied back when it is returned. As on hardware, lent and moved buffers
+//!   must be page-aligned and a multiple of the page size. Moved buffers must also
+//!   have come from `MapMemory`, such as a `HeapPageBuf`, or the move fails with
+//!   `BadAddress`.
+//! * `MapMemory` allocates page-aligned, zeroed memory from the host allocator.
+//! * A built-in name server answers at `xous-name-server`, using the same request
+//!   layout as the `ns` module.
+//! * A built-in ticktimer answers at `ticktimer-server`, measuring time with the
+//!   host clock. It also keeps the mutex and condition wait queues used by `sync`.
+
+extern crate std;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::string::String;
+use std::sync::{Condvar, LazyLock, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::host::{self, Registers, ok};
+use crate::{Error, InvokeType, Syscall, SyscallResult};
+
+/// The number of messages a server may have waiting before senders block.
+pub(crate) const QUEUE_DEPTH: usize = 32;
+
+/// Offsets into a name server request, matching the layout used by `ns`.
+const NAME_MAX_LENGTH: usize = 64;
+const NS_REGISTER: usize = 0;
+const NS_UNREGISTER: usize = 3;
+const NS_BLOCKING_CONNECT: usize = 6;
+const NS_TRY_CONNECT: usize = 7;
+
+/// Tags of the archived `Return` that answers a registration.
+const NS_RETURN_SID: u8 = 0;
+const NS_RETURN_FAILURE: u8 = 3;
+
+/// Ticktimer opcodes, matching the `ticktimer` module.
+const TT_ELAPSED_MS: usize = 0;
+const TT_SLEEP_MS: usize = 1;
+const TT_PING_WDT: usize = 4;
+const TT_LOCK_MUTEX: usize = 6;
+const TT_UNLOCK_MUTEX: usize = 7;
+const TT_WAIT_FOR_CONDITION: usize = 8;
+const TT_NOTIFY_CONDITION: usize = 9;
+const TT_FREE_MUTEX: usize = 10;
+const TT_FREE_CONDITION: usize = 11;
+
+struct Message {
+    sender: usize,
+    invoke_type: InvokeType,
+    opcode: usize,
+    args: [usize; 4],
+}
+
+enum Reply {
+    Waiting,
+    Scalar(SyscallResult, [usize; 5]),
+    Memory(usize, usize),
+    Failed(Error),
+}
+
+struct Pending {
+    server: [u32; 4],
+    reply: Reply,
+}
+
+struct Registration {
+    sid: [u32; 4],
+    max_connections: u32,
+    connections: u32,
+}
+
+struct Kernel {
+    servers: HashMap<[u32; 4], VecDeque<Message>>,
+    next_sid: u32,
+    connections: HashMap<u32, [u32; 4]>,
+    next_cid: u32,
+    pending: HashMap<usize, Pending>,
+    next_sender: usize,
+    names: HashMap<String, Registration>,
+    limits: HashMap<usize, usize>,
+    /// Unlocks that have not yet been claimed by `LockMutex`, by mutex.
+    mutex_unlocks: HashMap<usize, usize>,
+    /// Threads waiting on each condition, oldest first.
+    conditions: HashMap<usize, VecDeque<u64>>,
+    /// Waiting threads that have been notified but have not yet woken.
+    notified: HashSet<u64>,
+    next_waiter: u64,
+}
+
+struct State {
+    kernel: Mutex<Kernel>,
+    changed: Condvar,
+}
+
+static STATE: LazyLock<State> = LazyLock::new(|| {
+    let mut servers = HashMap::new();
+    servers.insert(name_server_sid(), VecDeque::new());
+    servers.insert(ticktimer_sid(), VecDeque::new());
+    let mut limits = HashMap::new();
+    limits.insert(crate::Limits::HeapMaximum as usize, 64 * 1024 * 1024);
+    limits.insert(crate::Limits::HeapSize as usize, 0);
+    State {
+        kernel: Mutex::new(Kernel {
+            servers,
+            next_sid: 1,
+            connections: HashMap::new(),
+            next_cid: 1,
+            pending: HashMap::new(),
+            next_sender: 1,
+            names: HashMap::new(),
+            limits,
+            mutex_unlocks: HashMap::new(),
+            conditions: HashMap::new(),
+            notified: HashSet::new(),
+            next_waiter: 1,
+        }),
+        changed: Condvar::new(),
+    }
+});
+
+fn ticktimer_sid() -> [u32; 4] {
+    crate::ServerAddress::try_from("ticktimer-server")
+        .unwrap()
+        .0
+}
+
+fn name_server_sid() -> [u32; 4] {
+    crate::ServerAddress::try_from("xous-name-server")
+        .unwrap()
+        .0
+}
+
+fn lock() -> MutexGuard<'static, Kernel> {
+    STATE.kernel.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn wait(kernel: MutexGuard<'static, Kernel>) -> MutexGuard<'static, Kernel> {
+    STATE
+        .changed
+        .wait(kernel)
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+fn wait_timeout(
+    kernel: MutexGuard<'static, Kernel>,
+    timeout: Duration,
+) -> MutexGuard<'static, Kernel> {
+    STATE
+        .changed
+        .wait_timeout(kernel, timeout)
+        .map(|(kernel, _)| kernel)
+        .unwrap_or_else(|e| e.into_inner().0)
+}
+
+fn notify() {
+    STATE.changed.notify_all();
+}
+
+fn is_page_aligned(addr: usize, len: usize) -> bool {
+    addr.is_multiple_of(host::PAGE_SIZE) && len.is_multiple_of(host::PAGE_SIZE)
+}
+
+fn sid_from(a: usize, b: usize, c: usize, d: usize) -> [u32; 4] {
+    [a as u32, b as u32, c as u32, d as u32]
+}
+
+/// Entry point called by `raw_syscall` in place of `ecall`.
+pub(crate) unsafe fn raw_syscall(a: [usize; 8]) -> Registers {
+    match unsafe { dispatch(a[0], [a[1], a[2], a[3], a[4], a[5], a[6], a[7]]) } {
+        Ok(result) => result,
+        Err(e) => (SyscallResult::Error as usize, e as usize, 0, 0, 0, 0, 0, 0),
+    }
+}
+
+unsafe fn dispatch(call: usize, a: [usize; 7]) -> Result<Registers, Error> {
+    match call {
+        c if c == Syscall::MapMemory as usize => map_memory(a[0], a[1], a[2]),
+        c if c == Syscall::UnmapMemory as usize => {
+            host::free_pages(a[0], a[1])?;
+            ok(SyscallResult::Ok, &[])
+        }
+        c if c == Syscall::UpdateMemoryFlags as usize => {
+            if !host::is_mapped(a[0], a[1]) {
+                return Err(Error::BadAddress);
+            }
+            ok(SyscallResult::Ok, &[])
+        }
+        c if c == Syscall::Yield as usize => {
+            std::thread::yield_now();
+            ok(SyscallResult::Ok, &[])
+        }
+        c if c == Syscall::CreateServer as usize => {
+            let mut kernel = lock();
+            let sid = new_sid(&mut kernel);
+            create_server(kernel, sid)
+        }
+        c if c == Syscall::CreateServerWithAddress as usize => {
+            create_server(lock(), sid_from(a[0], a[1], a[2], a[3]))
+        }
+        c if c == Syscall::DestroyServer as usize => {
+            destroy_server(sid_from(a[0], a[1], a[2], a[3]))
+        }
+        c if c == Syscall::Connect as usize => connect(sid_from(a[0], a[1], a[2], a[3]), true),
+        c if c == Syscall::TryConnect as usize => connect(sid_from(a[0], a[1], a[2], a[3]), false),
+        c if c == Syscall::Disconnect as usize => {
+            lock()
+                .connections
+                .remove(&(a[0] as u32))
+                .ok_or(Error::ServerNotFound)?;
+            ok(SyscallResult::Ok, &[])
+        }
+        c if c == Syscall::SendMessage as usize => unsafe { send_message(a, true) },
+        c if c == Syscall::TrySendMessage as usize => unsafe { send_message(a, false) },
+        c if c == Syscall::ReceiveMessage as usize => {
+            receive_message(sid_from(a[0], a[1], a[2], a[3]))
+        }
+        c if c == Syscall::ReturnScalar as usize => reply(
+            a[0],
+            Reply::Scalar(SyscallResult::Scalar5, [a[1], a[2], a[3], a[4], a[5]]),
+        ),
+        c if c == Syscall::ReturnScalar1 as usize => reply(
+            a[0],
+            Reply::Scalar(SyscallResult::Scalar1, [a[1], 0, 0, 0, 0]),
+        ),
+        c if c == Syscall::ReturnScalar2 as usize => reply(
+            a[0],
+            Reply::Scalar(SyscallResult::Scalar2, [a[1], a[2], 0, 0, 0]),
+        ),
+        c if c == Syscall::ReturnMemory as usize => reply(a[0], Reply::Memory(a[3], a[4])),
+        c if c == Syscall::CreateThread as usize => {
+            let tid = host::next_thread_id();
+            host::spawn_thread(tid, a[0], [a[3], a[4], a[5], a[6]])?;
+            ok(SyscallResult::ThreadId, &[tid])
+        }
+        c if c == Syscall::JoinThread as usize => {
+            ok(SyscallResult::Scalar1, &[host::join_thread(a[0])?])
+        }
+        c if c == Syscall::GetThreadId as usize => {
+            ok(SyscallResult::ThreadId, &[host::current_thread_id()])
+        }
+        c if c == Syscall::TerminateProcess as usize => std::process::exit(a[0] as i32),
+        c if c == Syscall::AdjustProcessLimit as usize => {
+            let mut kernel = lock();
+            let maximum = kernel.limits[&(crate::Limits::HeapMaximum as usize)];
+            let value = kernel.limits.get_mut(&a[0]).ok_or(Error::InvalidLimit)?;
+            // The heap may not grow past its maximum.
+            let allowed = a[0] != crate::Limits::HeapSize as usize || a[2] <= maximum;
+            if *value == a[1] && allowed {
+                *value = a[2];
+            }
+            // The kernel may answer with either form, so use both.
+            let kind = if a[0] == crate::Limits::HeapSize as usize {
+                SyscallResult::Scalar5
+            } else {
+                SyscallResult::Scalar2
+            };
+            ok(kind, &[a[0], *value])
+        }
+        _ => Err(Error::UnhandledSyscall),
+    }
+}
+
+fn map_memory(phys: usize, virt: usize, size: usize) -> Result<Registers, Error> {
+    if phys != 0 || virt != 0 {
+        return Err(Error::BadAddress);
+    }
+    let (addr, len) = host::alloc_pages(size)?;
+    ok(SyscallResult::MemoryRange, &[addr, len])
+}
+
+fn new_sid(kernel: &mut Kernel) -> [u32; 4] {
+    let sid = [0x6b63_6f6d, 0, 0, kernel.next_sid];
+    kernel.next_sid += 1;
+    sid
+}
+
+fn create_server(
+    mut kernel: MutexGuard<'static, Kernel>,
+    sid: [u32; 4],
+) -> Result<Registers, Error> {
+    if kernel.servers.contains_key(&sid) {
+        return Err(Error::ServerExists);
+    }
+    kernel.servers.insert(sid, VecDeque::new());
+    let cid = new_connection(&mut kernel, sid);
+    notify();
+    ok(
+        SyscallResult::NewServerId,
+        &[
+            sid[0] as usize,
+            sid[1] as usize,
+            sid[2] as usize,
+            sid[3] as usize,
+            cid as usize,
+        ],
+    )
+}
+
+fn destroy_server(sid: [u32; 4]) -> Result<Registers, Error> {
+    let mut kernel = lock();
+    let queue = kernel.servers.remove(&sid).ok_or(Error::ServerNotFound)?;
+    // Anybody still waiting on this server will never get an answer.
+    for message in queue {
+        if let Some(pending) = kernel.pending.get_mut(&message.sender) {
+            pending.reply = Reply::Failed(Error::ServerNotFound);
+        }
+    }
+    for pending in kernel.pending.values_mut() {
+        if pending.server == sid && matches!(pending.reply, Reply::Waiting) {
+            pending.reply = Reply::Failed(Error::ServerNotFound);
+        }
+    }
+    kernel.connections.retain(|_, target| *target != sid);
+    notify();
+    ok(SyscallResult::Ok, &[])
+}
+
+fn new_connection(kernel: &mut Kernel, sid: [u32; 4]) -> u32 {
+    if let Some((cid, _)) = kernel
+        .connections
+        .iter()
+        .find(|(_, target)| **target == sid)
+    {
+        return *cid;
+    }
+    let cid = kernel.next_cid;
+    kernel.next_cid += 1;
+    kernel.connections.insert(cid, sid);
+    cid
+}
+
+fn connect(sid: [u32; 4], blocking: bool) -> Result<Registers, Error> {
+    let mut kernel = lock();
+    while !kernel.servers.contains_key(&sid) {
+        if !blocking {
+            return Err(Error::ServerNotFound);
+        }
+        kernel = wait(kernel);
+    }
+    let cid = new_connection(&mut kernel, sid);
+    ok(SyscallResult::ConnectionId, &[cid as usize])
+}
+
+unsafe fn send_message(a: [usize; 7], blocking: bool) -> Result<Registers, Error> {
+    let invoke_type = InvokeType::try_from(a[1]).map_err(|_| Error::InvalidSyscall)?;
+    let (opcode, args) = (a[2], [a[3], a[4], a[5], a[6]]);
+    let mut kernel = lock();
+    let sid = *kernel
+        .connections
+        .get(&(a[0] as u32))
+        .ok_or(Error::ServerNotFound)?;
+
+    let is_memory = matches!(
+        invoke_type,
+        InvokeType::Lend | InvokeType::LendMut | InvokeType::Move
+    );
+    if is_memory && !is_page_aligned(args[0], args[1]) {
+        return Err(Error::BadAlignment);
+    }
+    if invoke_type == InvokeType::Move && args[1] != 0 && !host::is_mapped(args[0], args[1]) {
+        return Err(Error::BadAddress);
+    }
+
+    if sid == ticktimer_sid() {
+        return ticktimer(kernel, invoke_type, opcode, args);
+    }
+    if sid == name_server_sid() {
+        return unsafe { name_server(kernel, invoke_type, opcode, args, blocking) };
+    }
+
+    // Wait for room in the server's queue.
+    loop {
+        let queue = kernel.servers.get(&sid).ok_or(Error::ServerNotFound)?;
+        if queue.len() < QUEUE_DEPTH {
+            break;
+        }
+        if !blocking {
+            return Err(Error::ServerQueueFull);
+        }
+        kernel = wait(kernel);
+    }
+
+    let sender = kernel.next_sender;
+    kernel.next_sender += 1;
+
+    // Memory messages carry a copy of the buffer, owned by the server.
+    let mut message_args = args;
+    let client_buf = args[0];
+    if is_memory {
+        if invoke_type == InvokeType::Move {
+            // Memory from `MapMemory` is simply handed over.
+        } else if args[1] != 0 {
+            let (server_buf, _) = host::alloc_pages(args[1])?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    core::ptr::with_exposed_provenance::<u8>(client_buf),
+                    core::ptr::with_exposed_provenance_mut::<u8>(server_buf),
+                    args[1],
+                )
+            };
+            message_args[0] = server_buf;
+        }
+    }
+
+    let needs_reply = matches!(
+        invoke_type,
+        InvokeType::Lend | InvokeType::LendMut | InvokeType::BlockingScalar
+    );
+    if needs_reply {
+        kernel.pending.insert(
+            sender,
+            Pending {
+                server: sid,
+                reply: Reply::Waiting,
+            },
+        );
+    }
+    kernel
+        .servers
+        .get_mut(&sid)
+        .ok_or(Error::ServerNotFound)?
+        .push_back(Message {
+            sender,
+            invoke_type,
+            opcode,
+            args: message_args,
+        });
+    notify();
+
+    if !needs_reply {
+        return ok(SyscallResult::Ok, &[]);
+    }
+
+    while matches!(kernel.pending[&sender].reply, Reply::Waiting) {
+        kernel = wait(kernel);
+    }
+    let pending = kernel.pending.remove(&sender).unwrap();
+    let server_buf = message_args[0];
+    let result = match pending.reply {
+        Reply::Scalar(kind, values) => ok(kind, &values),
+        Reply::Memory(offset, valid) => {
+            if invoke_type == InvokeType::LendMut && args[1] != 0 {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        core::ptr::with_exposed_provenance::<u8>(server_buf),
+                        core::ptr::with_exposed_provenance_mut::<u8>(client_buf),
+                        args[1],
+                    )
+                };
+            }
+            ok(SyscallResult::MemoryReturned, &[offset, valid])
+        }
+        Reply::Failed(e) => Err(e),
+        Reply::Waiting => unreachable!(),
+    };
+    if is_memory && args[1] != 0 {
+        let _ = host::free_pages(server_buf, args[1]);
+    }
+    result
+}
+
+fn receive_message(sid: [u32; 4]) -> Result<Registers, Error> {
+    let mut kernel = lock();
+    loop {
+        let queue = kernel.servers.get_mut(&sid).ok_or(Error::ServerNotFound)?;
+        if let Some(message) = queue.pop_front() {
+            notify();
+            return ok(
+                SyscallResult::Message,
+                &[
+                    message.sender,
+                    message.invoke_type as usize,
+                    message.opcode,
+                    message.args[0],
+                    message.args[1],
+                    message.args[2],
+                    message.args[3],
+                ],
+            );
+        }
+        kernel = wait(kernel);
+    }
+}
+
+fn reply(sender: usize, reply: Reply) -> Result<Registers, Error> {
+    let mut kernel = lock();
+    let pending = kernel
+        .pending
+        .get_mut(&sender)
+        .filter(|pending| matches!(pending.reply, Reply::Waiting))
+        .ok_or(Error::ThreadNotAvailable)?;
+    pending.reply = reply;
+    notify();
+    ok(SyscallResult::Ok, &[])
+}
+
+/// Handles requests to the built-in ticktimer, which are answered in place
+/// rather than being queued.
+fn ticktimer(
+    mut kernel: MutexGuard<'static, Kernel>,
+    invoke_type: InvokeType,
+    opcode: usize,
+    args: [usize; 4],
+) -> Result<Registers, Error> {
+    static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+    if invoke_type == InvokeType::Scalar {
+        match opcode {
+            TT_PING_WDT => {}
+            TT_FREE_MUTEX => {
+                kernel.mutex_unlocks.remove(&args[0]);
+            }
+            TT_FREE_CONDITION => {
+                kernel.conditions.remove(&args[0]);
+            }
+            _ => return Err(Error::InvalidArgument),
+        }
+        return ok(SyscallResult::Ok, &[]);
+    }
+    if invoke_type != InvokeType::BlockingScalar {
+        return Err(Error::InvalidArgument);
+    }
+    match opcode {
+        TT_ELAPSED_MS => {
+            let ms = START.elapsed().as_millis() as u64;
+            ok(
+                SyscallResult::Scalar2,
+                &[ms as u32 as usize, (ms >> 32) as usize],
+            )
+        }
+        TT_SLEEP_MS => {
+            drop(kernel);
+            std::thread::sleep(Duration::from_millis(args[0] as u64));
+            ok(SyscallResult::Scalar1, &[0])
+        }
+        TT_LOCK_MUTEX => {
+            loop {
+                if let Some(unlocks) = kernel.mutex_unlocks.get_mut(&args[0])
+                    && *unlocks > 0
+                {
+                    *unlocks -= 1;
+                    break;
+                }
+                kernel = wait(kernel);
+            }
+            ok(SyscallResult::Scalar1, &[0])
+        }
+        TT_UNLOCK_MUTEX => {
+            *kernel.mutex_unlocks.entry(args[0]).or_default() += 1;
+            notify();
+            ok(SyscallResult::Scalar1, &[0])
+        }
+        TT_WAIT_FOR_CONDITION => {
+            let waiter = kernel.next_waiter;
+            kernel.next_waiter += 1;
+            kernel
+                .conditions
+                .entry(args[0])
+                .or_default()
+                .push_back(waiter);
+            let deadline =
+                (args[1] != 0).then(|| Instant::now() + Duration::from_millis(args[1] as u64));
+            loop {
+                if kernel.notified.remove(&waiter) {
+                    return ok(SyscallResult::Scalar1, &[0]);
+                }
+                match deadline {
+                    Some(deadline) if Instant::now() >= deadline => break,
+                    Some(deadline) => {
+                        kernel = wait_timeout(kernel, deadline - Instant::now());
+                    }
+                    None => kernel = wait(kernel),
+                }
+            }
+            if let Some(waiters) = kernel.conditions.get_mut(&args[0]) {
+                waiters.retain(|&w| w != waiter);
+            }
+            ok(SyscallResult::Scalar1, &[1])
+        }
+        TT_NOTIFY_CONDITION => {
+            let mut woken = 0;
+            while woken < args[1] {
+                let Some(waiter) = kernel
+                    .conditions
+                    .get_mut(&args[0])
+                    .and_then(VecDeque::pop_front)
+                else {
+                    break;
+                };
+                kernel.notified.insert(waiter);
+                woken += 1;
+            }
+            notify();
+            ok(SyscallResult::Scalar1, &[woken])
+        }
+        _ => Err(Error::InvalidArgument),
+    }
+}
+
+/// Handles requests to the built-in name server, which are answered in place
+/// rather than being queued.
+unsafe fn name_server(
+    mut kernel: MutexGuard<'static, Kernel>,
+    invoke_type: InvokeType,
+    opcode: usize,
+    args: [usize; 4],
+    blocking: bool,
+) -> Result<Registers, Error> {
+    if opcode == NS_UNREGISTER {
+        if invoke_type != InvokeType::BlockingScalar {
+            return Err(Error::InvalidArgument);
+        }
+        let sid = sid_from(args[0], args[1], args[2], args[3]);
+        let before = kernel.names.len();
+        kernel
+            .names
+            .retain(|_, registration| registration.sid != sid);
+        let removed = kernel.names.len() != before;
+        return ok(SyscallResult::Scalar1, &[removed as usize]);
+    }
+
+    let (addr, len) = (args[0], args[1]);
+    if invoke_type != InvokeType::LendMut || len < NAME_MAX_LENGTH + 24 {
+        return Err(Error::InvalidArgument);
+    }
+    let data = unsafe {
+        core::slice::from_raw_parts_mut(core::ptr::with_exposed_provenance_mut(addr), len)
+    };
+    let word = |data: &[u8], offset: usize| {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    };
+    let name_len = (word(data, NAME_MAX_LENGTH) as usize).min(NAME_MAX_LENGTH);
+    let name = String::from_utf8_lossy(&data[..name_len]).into_owned();
+
+    if opcode == NS_REGISTER {
+        // The request is an archived `Registration`, and is answered with an
+        // archived `Return` at offset 0.
+        let max_connections = if data[NAME_MAX_LENGTH + 4] != 0 {
+            word(data, NAME_MAX_LENGTH + 8)
+        } else {
+            0
+        };
+        let sid = new_sid(&mut kernel);
+        data[..20].fill(0);
+        match kernel.names.entry(name) {
+            Entry::Occupied(_) => data[0] = NS_RETURN_FAILURE,
+            Entry::Vacant(entry) => {
+                entry.insert(Registration {
+                    sid,
+                    max_connections,
+                    connections: 0,
+                });
+                notify();
+                data[0] = NS_RETURN_SID;
+                for (dest, word) in data[4..20].chunks_exact_mut(4).zip(sid) {
+                    dest.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+        return ok(SyscallResult::MemoryReturned, &[0, 20]);
+    }
+
+    let result: Result<u32, Error> = match opcode {
+        NS_BLOCKING_CONNECT | NS_TRY_CONNECT => loop {
+            if let Some(registration) = kernel.names.get_mut(&name) {
+                if registration.max_connections != 0
+                    && registration.connections >= registration.max_connections
+                {
+                    break Err(Error::AccessDenied);
+                }
+                registration.connections += 1;
+                let sid = registration.sid;
+                break Ok(new_connection(&mut kernel, sid));
+            }
+            if opcode == NS_TRY_CONNECT || !blocking {
+                break Err(Error::NotFound);
+            }
+            kernel = wait(kernel);
+        },
+        _ => Err(Error::InvalidArgument),
+    };
+
+    match result {
+        Ok(cid) => {
+            data[0..4].copy_from_slice(&0u32.to_le_bytes());
+            data[4..8].copy_from_slice(&cid.to_le_bytes());
+        }
+        Err(e) => {
+            data[0..4].copy_from_slice(&1u32.to_le_bytes());
+            data[4..8].copy_from_slice(&(e as u32).to_le_bytes());
+        }
+    }
+    ok(SyscallResult::MemoryReturned, &[0, 0])
+}
+
+/// Helpers for the tests of each module, which run against this kernel.
+#[cfg(test)]
+pub(crate) mod test_util {
+    use crate::ServerAddress;
+
+    pub(crate) fn address(name: &str) -> ServerAddress {
+        ServerAddress::try_from(name).unwrap()
+    }
+}
@@ -1,85 +1,123 @@
 //! Xous Nameserver client, for connecting to Services that have
 //! no well-defined name and instead register a Server with the
-//! global Nameserver.
+//! global Nameserver. Servers may also be registered with the Nameserver
+//! so that other processes can connect to them by name.
 
 use core::sync::atomic::{AtomicU32, Ordering};
 
-use crate::Connection;
+use crate::{Connection, Error, OwnedConnection, ServerId};
 
 mod ns {
     const NAME_MAX_LENGTH: usize = 64;
-    use crate::{Connection, lend_mut};
-
-    // By making this repr(C), the layout of this struct becomes well-defined
-    // and no longer shifts around.
-    // By marking it as `align(4096)` we define that it will be page-aligned,
-    // meaning it can be sent between processes. We make sure to pad out the
-    // entire struct so that memory isn't leaked to the name server.
-    #[repr(C, align(4096))]
-    struct ConnectRequest {
-        data: [u8; 4096],
+    use crate::{Connection, Error, MessageBuf, MessageReader, ServerId, blocking_scalar};
+
+    // Registration requests are laid out the way `rkyv` archives the name
+    // server's `Registration { name: xous_ipc::String<64>, conn_limit: Option<u32> }`:
+    // the name as a fixed-capacity string, then an `Option` tag byte and the
+    // `u32` limit at the next aligned offset. Connection requests carry only
+    // the name. See `Registration` and `Return` in
+    // `services/xous-names/src/api.rs` of xous-core, and `register_name()` in
+    // `services/xous-names/src/lib.rs`.
+    fn name_request(name: &str) -> Result<MessageBuf, Error> {
+        let mut request = MessageBuf::new();
+        request.put_fixed_str(name, NAME_MAX_LENGTH)?;
+        Ok(request)
+    }
+
+    // The name server overwrites the request with an archived `Return`, whose
+    // tag byte is followed by the new server ID when registration succeeded.
+    const RETURN_SID: u8 = 0;
+
+    /// Names longer than `NAME_MAX_LENGTH` are rejected rather than truncated,
+    /// since a truncated name may match a different server.
+    fn check_name(name: &str) -> Result<(), Error> {
+        if name.len() > NAME_MAX_LENGTH {
+            return Err(Error::InvalidString);
+        }
+        Ok(())
+    }
+
+    /// Decodes the reply that the nameserver writes over a connection
+    /// request: a `u32` that is `0` on success, followed by a `u32` holding
+    /// either the connection ID or, on failure, the error code. See
+    /// `connect_with_name_impl()` in `services/xous-names/src/lib.rs` of
+    /// xous-core.
+    pub fn connect_reply(response: &mut MessageReader) -> Result<Connection, Error> {
+        let failed = response.get_u32()? != 0;
+        let value = response.get_u32()?;
+        if failed {
+            Err(Error::from(value as usize))
+        } else {
+            Ok(value.into())
+        }
+    }
+
+    /// Asks the name server to reserve `name`, returning the server ID it
+    /// chose. The caller must then create the server with that ID.
+    pub fn register_name(name: &str, max_connections: Option<u32>) -> Result<ServerId, Error> {
+        check_name(name)?;
+        let mut request = name_request(name)?;
+        request.put_bool(max_connections.is_some())?;
+        request.put_u32(max_connections.unwrap_or(0))?;
+        let valid = request.len();
+        let (offset, _) =
+            request.lend_mut(super::name_server()?, 0 /* Register */, 0, valid)?;
+
+        let mut response = request.reader_at(offset);
+        if response.get_u8()? != RETURN_SID {
+            // The name server refuses names that are already registered.
+            return Err(Error::ServerExists);
+        }
+        let mut sid = [0u32; 4];
+        for word in sid.iter_mut() {
+            *word = response.get_u32()?;
+        }
+        Ok(ServerId(sid))
     }
 
-    impl ConnectRequest {
-        pub fn new(name: &str) -> Self {
-            let mut cr = ConnectRequest { data: [0u8; 4096] };
-            let name_bytes = name.as_bytes();
-
-            // Copy the string into our backing store.
-            for (&src_byte, dest_byte) in name_bytes.iter().zip(&mut cr.data[0..NAME_MAX_LENGTH]) {
-                *dest_byte = src_byte;
-            }
-
-            // Set the string length to the length of the passed-in String,
-            // or the maximum possible length. Which ever is smaller.
-            for (&src_byte, dest_byte) in (name.len().min(NAME_MAX_LENGTH) as u32)
-                .to_le_bytes()
-                .iter()
-                .zip(&mut cr.data[NAME_MAX_LENGTH..])
-            {
-                *dest_byte = src_byte;
-            }
-            cr
+    /// Removes the name that maps to `sid`. The name server answers with `1`
+    /// if the server was registered, and `0` otherwise.
+    pub fn unregister_server(sid: ServerId) -> Result<(), Error> {
+        let result = blocking_scalar(
+            super::name_server()?,
+            [
+                3, /* Unregister */
+                sid.0[0] as usize,
+                sid.0[1] as usize,
+                sid.0[2] as usize,
+                sid.0[3] as usize,
+            ],
+        )?;
+        if result[0] == 0 {
+            return Err(Error::ServerNotFound);
         }
+        Ok(())
     }
 
-    pub fn connect_with_name_impl(name: &str, blocking: bool) -> Option<Connection> {
-        let mut request = ConnectRequest::new(name);
+    pub fn connect_with_name_impl(name: &str, blocking: bool) -> Result<Connection, Error> {
+        check_name(name)?;
+        let mut request = name_request(name)?;
         let opcode = if blocking {
             6 /* BlockingConnect */
         } else {
             7 /* TryConnect */
         };
         let cid = if blocking {
-            super::name_server()
+            super::name_server()?
         } else {
             super::try_name_server()?
         };
 
-        lend_mut(
-            cid,
-            opcode,
-            &mut request.data,
-            0,
-            name.len().min(NAME_MAX_LENGTH),
-        )
-        .expect("unable to perform lookup");
-
-        // Read the result code back from the nameserver
-        let result = u32::from_le_bytes(request.data[0..4].try_into().unwrap());
-        if result == 0 {
-            // If the result was successful, then the CID is stored in the next 4 bytes
-            Some(u32::from_le_bytes(request.data[4..8].try_into().unwrap()).into())
-        } else {
-            None
-        }
+        request.lend_mut(cid, opcode, 0, name.len())?;
+
+        connect_reply(&mut request.reader())
     }
 
-    pub fn connect_with_name(name: &str) -> Option<Connection> {
+    pub fn connect_with_name(name: &str) -> Result<Connection, Error> {
         connect_with_name_impl(name, true)
     }
 
-    pub fn try_connect_with_name(name: &str) -> Option<Connection> {
+    pub fn try_connect_with_name(name: &str) -> Result<Connection, Error> {
         connect_with_name_impl(name, false)
     }
 }
@@ -90,47 +128,170 @@ mod ns {
 /// Note that this is different from connecting to a server by address. Server
 /// addresses are always 16 bytes long, whereas server names are arbitrary-length
 /// strings up to 64 bytes in length.
-pub fn connect(name: &str) -> Option<Connection> {
+///
+/// Returns `Error::InvalidString` if the name is longer than 64 bytes, or the
+/// error reported by the name server if the lookup fails.
+pub fn connect(name: &str) -> Result<Connection, Error> {
     ns::connect_with_name(name)
 }
 
+/// Connects to a server by name, returning a connection that is disconnected
+/// when dropped. If the server does not exist, this will block until the server
+/// is created.
+///
+/// # Safety
+///
+/// The name server hands out the same connection ID each time this process
+/// connects to a given server, so dropping the returned value disconnects every
+/// other `Connection` to that server as well. The caller must ensure that no
+/// other part of the program is using a connection to this server, and that
+/// none will be made while the returned value is alive.
+pub unsafe fn connect_owned(name: &str) -> Result<OwnedConnection, Error> {
+    let connection = connect(name)?;
+    // The caller guarantees that nothing else is using this connection.
+    Ok(unsafe { OwnedConnection::from_raw(connection) })
+}
+
 /// Attempts to connect to a server by name. If the server does not exist, this will
-/// immediately return `None`.
+/// immediately return the error reported by the name server, which is typically
+/// `Error::NotFound`. If the name server itself is not running, this returns
+/// `Error::ServerNotFound`.
 ///
 /// Note that this is different from connecting to a server by address. Server
 /// addresses are always 16 bytes long, whereas server names are arbitrary-length
-/// strings.
-pub fn try_connect(name: &str) -> Option<Connection> {
+/// strings up to 64 bytes in length.
+pub fn try_connect(name: &str) -> Result<Connection, Error> {
     ns::try_connect_with_name(name)
 }
 
+/// Registers `name` with the name server and creates a server for it,
+/// allowing other processes to `connect()` to it by name. The name server
+/// chooses the server's ID.
+///
+/// `max_connections` limits how many connections the name server will hand
+/// out for this name. Pass `None` to allow any number of connections.
+///
+/// Returns `Error::ServerExists` if the name is already registered.
+pub fn register(name: &str, max_connections: Option<u32>) -> Result<ServerId, Error> {
+    let sid = ns::register_name(name, max_connections)?;
+    match crate::create_server_with_address(sid.into()) {
+        Ok(server) => Ok(server),
+        Err(e) => {
+            let _ = ns::unregister_server(sid);
+            Err(e)
+        }
+    }
+}
+
+/// Removes a server that was created with `register()` from the name server,
+/// then destroys it.
+pub fn unregister(server: ServerId) -> Result<(), Error> {
+    ns::unregister_server(server)?;
+    crate::destroy_server(server)
+}
+
 static NAME_SERVER_CONNECTION: AtomicU32 = AtomicU32::new(0);
 
 /// Returns a `Connection` to the name server. If the name server has not been started,
 /// then this call will block until the name server has been started. The `Connection`
 /// will be shared among all connections in a process, so it is safe to call this
 /// multiple times.
-pub(crate) fn name_server() -> Connection {
+pub(crate) fn name_server() -> Result<Connection, Error> {
     let cid = NAME_SERVER_CONNECTION.load(Ordering::Relaxed);
     if cid != 0 {
-        return cid.into();
+        return Ok(cid.into());
     }
 
-    let cid = crate::connect("xous-name-server".try_into().unwrap()).unwrap();
+    let cid = crate::connect("xous-name-server".try_into().unwrap())?;
     NAME_SERVER_CONNECTION.store(cid.into(), Ordering::Relaxed);
-    cid
+    Ok(cid)
 }
 
-fn try_name_server() -> Option<Connection> {
+/// Returns a `Connection` to the name server, or `Error::ServerNotFound` if the
+/// name server has not yet been started.
+fn try_name_server() -> Result<Connection, Error> {
     let cid = NAME_SERVER_CONNECTION.load(Ordering::Relaxed);
     if cid != 0 {
-        return Some(cid.into());
+        return Ok(cid.into());
+    }
+
+    let cid =
+        crate::try_connect("xous-name-server".try_into().unwrap())?.ok_or(Error::ServerNotFound)?;
+    NAME_SERVER_CONNECTION.store(cid.into(), Ordering::Relaxed);
+    Ok(cid)
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use crate::*;
+
+    #[test]
+    fn name_server() {
+        assert!(matches!(
+            ns::try_connect("mock-unnamed"),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            ns::register(&"x".repeat(65), None),
+            Err(Error::InvalidString)
+        ));
+        assert!(matches!(
+            ns::connect(&"x".repeat(65)),
+            Err(Error::InvalidString)
+        ));
+
+        let sid = ns::register("mock-named", Some(2)).unwrap();
+        assert!(matches!(
+            ns::register("mock-named", None),
+            Err(Error::ServerExists)
+        ));
+        let server = thread::spawn(move || {
+            let envelope = receive_message(sid).unwrap();
+            let opcode = envelope.opcode();
+            envelope.return_scalar([opcode, 0, 0, 0, 0]).unwrap();
+        });
+        let cid = ns::connect("mock-named").unwrap();
+        assert_eq!(blocking_scalar(cid, [5, 0, 0, 0, 0]).unwrap()[0], 5);
+        server.join().unwrap();
+
+        // The second connection is the last one allowed.
+        ns::try_connect("mock-named").unwrap();
+        assert!(matches!(
+            ns::try_connect("mock-named"),
+            Err(Error::AccessDenied)
+        ));
+
+        ns::unregister(sid).unwrap();
+        assert!(matches!(
+            ns::try_connect("mock-named"),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(ns::unregister(sid), Err(Error::ServerNotFound)));
     }
 
-    if let Ok(Some(cid)) = crate::try_connect("xous-name-server".try_into().unwrap()) {
-        NAME_SERVER_CONNECTION.store(cid.into(), Ordering::Relaxed);
-        Some(cid)
-    } else {
-        None
+    #[test]
+    fn connect_reply_layout() {
+        use super::ns::connect_reply;
+
+        let success = [0, 0, 0, 0, 7, 0, 0, 0];
+        let cid: u32 = connect_reply(&mut MessageReader::new(&success))
+            .unwrap()
+            .into();
+        assert_eq!(cid, 7);
+
+        let failure = [1, 0, 0, 0, 28, 0, 0, 0];
+        assert!(matches!(
+            connect_reply(&mut MessageReader::new(&failure)),
+            Err(Error::NotFound)
+        ));
+        let failure = [1, 0, 0, 0, 23, 0, 0, 0];
+        assert!(matches!(
+            connect_reply(&mut MessageReader::new(&failure)),
+            Err(Error::AccessDenied)
+        ));
     }
 }
@@ -0,0 +1,270 @@
+//! Retrying operations that fail with a transient error, such as sending a
+//! message to a server whose mailbox is full.
+//!
+//! A [RetryPolicy] may be passed to [Message::send](crate::Message::send) as
+//! `SendMode::Retry`, or used to wrap any other call with [RetryPolicy::retry]:
+//!
+//! ```text
+//! let policy = RetryPolicy::new().max_attempts(5);
+//! policy.retry(|| try_scalar(connection, [1, 2, 3, 4, 5]))?;
+//! ```
+
+use core::time::Duration;
+
+use crate::{Error, do_yield, ticktimer};
+
+/// How long to wait between attempts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backoff {
+    /// Give up the rest of the current time slice with `do_yield`.
+    Yield,
+    /// Sleep using the ticktimer, starting at `initial` and doubling after
+    /// each attempt up to `max`. Both are rounded down to whole milliseconds,
+    /// but never below 1ms. Until the ticktimer server has started, this
+    /// yields instead.
+    Sleep { initial: Duration, max: Duration },
+}
+
+/// Describes how often, and for how long, an operation is retried while it
+/// fails with an error for which `Error::is_transient` returns `true`. Other
+/// errors are returned immediately.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: Option<u32>,
+    backoff: Backoff,
+    deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Retries indefinitely, sleeping for 1ms after the first attempt and
+    /// doubling up to 100ms.
+    pub const fn new() -> Self {
+        RetryPolicy {
+            max_attempts: None,
+            backoff: Backoff::Sleep {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(100),
+            },
+            deadline: None,
+        }
+    }
+
+    /// Gives up after `attempts` attempts, including the first, returning the
+    /// last error. A value of `0` is treated as `1`.
+    pub const fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(if attempts == 0 { 1 } else { attempts });
+        self
+    }
+
+    /// Sets how long to wait between attempts.
+    pub const fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Gives up with `Error::Timeout` once `deadline` has passed since the
+    /// first attempt. Time is measured with the ticktimer server, so the
+    /// deadline only begins to run once that has started.
+    pub const fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Calls `f` until it succeeds, fails with an error that is not
+    /// transient, or the policy gives up.
+    pub fn retry<T>(&self, mut f: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut last_error = Error::InternalError;
+        let result = self.run(|| match f() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.is_transient() => {
+                last_error = e;
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        })?;
+        result.ok_or(last_error)
+    }
+
+    /// Calls `poll` until it returns a value, giving up with `Error::Timeout`
+    /// if the policy runs out of attempts.
+    pub(crate) fn poll<T>(
+        &self,
+        poll: impl FnMut() -> Result<Option<T>, Error>,
+    ) -> Result<T, Error> {
+        self.run(poll)?.ok_or(Error::Timeout)
+    }
+
+    /// Returns `Ok(None)` if every attempt was used up.
+    fn run<T>(
+        &self,
+        mut attempt: impl FnMut() -> Result<Option<T>, Error>,
+    ) -> Result<Option<T>, Error> {
+        let deadline_ms = self
+            .deadline
+            .map(|deadline| u64::try_from(deadline.as_millis()).unwrap_or(u64::MAX));
+        let (mut backoff_ms, max_backoff_ms) = match self.backoff {
+            Backoff::Yield => (0, 0),
+            // A sleep of 0ms returns at once and never grows by doubling, which
+            // would turn the backoff into a busy loop.
+            Backoff::Sleep { initial, max } => (
+                u64::try_from(initial.as_millis())
+                    .unwrap_or(u64::MAX)
+                    .max(1),
+                u64::try_from(max.as_millis()).unwrap_or(u64::MAX).max(1),
+            ),
+        };
+        let needs_time = deadline_ms.is_some() || self.backoff != Backoff::Yield;
+        let now_ms = || -> Result<Option<u64>, Error> {
+            if needs_time && ticktimer::try_ticktimer()?.is_some() {
+                Ok(Some(ticktimer::elapsed_ms()?))
+            } else {
+                Ok(None)
+            }
+        };
+
+        // The deadline runs from the first attempt, or from when the ticktimer
+        // server is first found if it has not started yet.
+        let mut start = if deadline_ms.is_some() {
+            now_ms()?
+        } else {
+            None
+        };
+        let mut attempts = 0u32;
+        loop {
+            if let Some(value) = attempt()? {
+                return Ok(Some(value));
+            }
+            attempts = attempts.saturating_add(1);
+            if self.max_attempts.is_some_and(|max| attempts >= max) {
+                return Ok(None);
+            }
+            if deadline_ms == Some(0) {
+                return Err(Error::Timeout);
+            }
+
+            let now = now_ms()?;
+            let mut remaining_ms = u64::MAX;
+            if let (Some(deadline_ms), Some(now)) = (deadline_ms, now) {
+                let elapsed_ms = now.saturating_sub(*start.get_or_insert(now));
+                if elapsed_ms >= deadline_ms {
+                    return Err(Error::Timeout);
+                }
+                remaining_ms = deadline_ms - elapsed_ms;
+            }
+
+            if now.is_some() && self.backoff != Backoff::Yield {
+                ticktimer::sleep_ms(backoff_ms.min(remaining_ms) as usize)?;
+                backoff_ms = backoff_ms.saturating_mul(2).min(max_backoff_ms);
+            } else {
+                do_yield();
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::mock::QUEUE_DEPTH;
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn retry_policy() {
+        assert!(Error::ServerQueueFull.is_transient());
+        assert!(!Error::ServerNotFound.is_transient());
+        assert!(!Error::Timeout.is_transient());
+
+        let mut calls = 0;
+        let result: Result<(), Error> = RetryPolicy::new().max_attempts(3).retry(|| {
+            calls += 1;
+            Err(Error::Unavailable)
+        });
+        assert!(matches!(result, Err(Error::Unavailable)));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), Error> = RetryPolicy::new().retry(|| {
+            calls += 1;
+            Err(Error::AccessDenied)
+        });
+        assert!(matches!(result, Err(Error::AccessDenied)));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let policy = RetryPolicy::new().backoff(Backoff::Yield);
+        let result = policy.retry(|| {
+            calls += 1;
+            if calls < 5 {
+                Err(Error::ServerQueueFull)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 5);
+
+        // The deadline includes the time taken by the first attempt.
+        let mut calls = 0;
+        let policy = RetryPolicy::new().deadline(Duration::from_millis(20));
+        let result: Result<(), Error> = policy.retry(|| {
+            calls += 1;
+            thread::sleep(Duration::from_millis(30));
+            Err(Error::ServerQueueFull)
+        });
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert_eq!(calls, 1);
+
+        // A zero backoff still sleeps for 1ms between attempts.
+        let mut calls = 0;
+        let policy = RetryPolicy::new()
+            .backoff(Backoff::Sleep {
+                initial: Duration::ZERO,
+                max: Duration::ZERO,
+            })
+            .deadline(Duration::from_millis(20));
+        let result: Result<(), Error> = policy.retry(|| {
+            calls += 1;
+            Err(Error::ServerQueueFull)
+        });
+        assert!(matches!(result, Err(Error::Timeout)));
+        assert!(calls <= 21, "{} attempts in 20ms", calls);
+
+        let sid = create_server_with_address(address("mock-retry")).unwrap();
+        let cid = connect(address("mock-retry")).unwrap();
+        for _ in 0..QUEUE_DEPTH {
+            try_scalar(cid, [0; 5]).unwrap();
+        }
+        let policy = RetryPolicy::new().deadline(Duration::from_millis(20));
+        assert!(matches!(
+            policy.retry(|| try_scalar(cid, [0; 5])),
+            Err(Error::Timeout)
+        ));
+        let policy = RetryPolicy::new().max_attempts(2);
+        assert!(matches!(
+            Message::scalar().send(cid, SendMode::Retry(policy)),
+            Err(Error::ServerQueueFull)
+        ));
+
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for _ in 0..=QUEUE_DEPTH {
+                receive_message(sid).unwrap();
+            }
+        });
+        Message::scalar()
+            .send(cid, SendMode::Retry(RetryPolicy::new()))
+            .unwrap();
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+//! A builder for outgoing messages. Every way of sending a message goes
+//! through [Message::send], and [SendMode] selects how it waits for room in
+//! the server's mailbox:
+//!
+//! ```text
+//! let (offset, valid) = Message::lend_mut(&mut buf)
+//!     .opcode(3)
+//!     .args(0, len)
+//!     .send(connection, SendMode::Try)?;
+//! ```
+//!
+//! The free functions such as `lend_mut` and `try_scalar` are shorthands for
+//! the corresponding builder.
+
+use core::time::Duration;
+
+use crate::validate::{self, LendGuard};
+use crate::{Connection, Error, InvokeType, PageAligned, RetryPolicy, Syscall, syscall};
+
+/// How a message waits when the server's mailbox is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SendMode {
+    /// Block until there is room in the mailbox.
+    Block,
+    /// Return `Error::ServerQueueFull` immediately.
+    Try,
+    /// Retry while the mailbox is full, sleeping for an increasing interval
+    /// between attempts, and return `Error::Timeout` once the duration has
+    /// passed. This is shorthand for a `Retry` with a deadline.
+    Timeout(Duration),
+    /// Retry according to the policy while sending fails with a transient
+    /// error.
+    Retry(RetryPolicy),
+}
+
+mod private {
+    use crate::{Error, InvokeType};
+
+    pub trait Sealed: Sized {
+        const INVOKE_TYPE: InvokeType;
+
+        /// Returns the registers that follow the opcode.
+        fn registers(&self, args: [usize; 4]) -> [usize; 4];
+
+        /// Returns the lent buffer, and whether it is lent mutably.
+        fn lent(&self) -> Option<(&[u8], bool)> {
+            None
+        }
+
+        /// Called with the result of the syscall that sent the message.
+        fn sent(self, result: Result<[usize; 5], Error>) -> Result<[usize; 5], Error> {
+            result
+        }
+    }
+}
+
+/// The way a [Message] is sent, which determines what its buffer is and what
+/// is returned once it has been sent.
+///
+/// This trait is sealed. It is implemented by [Scalar], [BlockingScalar],
+/// [Lend], [LendMut] and, with the `unstable_mem` feature, [Move].
+pub trait MessageKind: private::Sealed {
+    /// The value returned by [Message::send].
+    type Output;
+
+    /// Converts the registers returned by the kernel into the output.
+    fn output(result: [usize; 5]) -> Self::Output;
+}
+
+/// A message sent with `scalar`, which does not wait for a response.
+pub struct Scalar;
+
+/// A message sent with `blocking_scalar`, which returns five values.
+pub struct BlockingScalar;
+
+/// A buffer lent with `lend`, which returns an `(offset, valid)` pair.
+pub struct Lend<'a>(&'a [u8]);
+
+/// A buffer lent with `lend_mut`, which returns an `(offset, valid)` pair.
+pub struct LendMut<'a>(&'a mut [u8]);
+
+fn memory_registers(data: &[u8], args: [usize; 4]) -> [usize; 4] {
+    [data.as_ptr() as usize, data.len(), args[0], args[1]]
+}
+
+impl private::Sealed for Scalar {
+    const INVOKE_TYPE: InvokeType = InvokeType::Scalar;
+
+    fn registers(&self, args: [usize; 4]) -> [usize; 4] {
+        args
+    }
+}
+
+impl MessageKind for Scalar {
+    type Output = ();
+
+    fn output(_result: [usize; 5]) {}
+}
+
+impl private::Sealed for BlockingScalar {
+    const INVOKE_TYPE: InvokeType = InvokeType::BlockingScalar;
+
+    fn registers(&self, args: [usize; 4]) -> [usize; 4] {
+        args
+    }
+}
+
+impl MessageKind for BlockingScalar {
+    type Output = [usize; 5];
+
+    fn output(result: [usize; 5]) -> [usize; 5] {
+        result
+    }
+}
+
+impl private::Sealed for Lend<'_> {
+    const INVOKE_TYPE: InvokeType = InvokeType::Lend;
+
+    fn registers(&self, args: [usize; 4]) -> [usize; 4] {
+        memory_registers(self.0, args)
+    }
+
+    fn lent(&self) -> Option<(&[u8], bool)> {
+        Some((self.0, false))
+    }
+}
+
+impl MessageKind for Lend<'_> {
+    type Output = (usize, usize);
+
+    fn output(result: [usize; 5]) -> (usize, usize) {
+        (result[0], result[1])
+    }
+}
+
+impl private::Sealed for LendMut<'_> {
+    const INVOKE_TYPE: InvokeType = InvokeType::LendMut;
+
+    fn registers(&self, args: [usize; 4]) -> [usize; 4] {
+        memory_registers(self.0, args)
+    }
+
+    fn lent(&self) -> Option<(&[u8], bool)> {
+        Some((self.0, true))
+    }
+}
+
+impl MessageKind for LendMut<'_> {
+    type Output = (usize, usize);
+
+    fn output(result: [usize; 5]) -> (usize, usize) {
+        (result[0], result[1])
+    }
+}
+
+/// A buffer moved to the server, which does not wait for a response.
+///
+/// The memory is only handed over once the message is delivered, so the
+/// message may be retried while the server's mailbox is full. If sending
+/// ultimately fails the memory is leaked rather than released, as `move`
+/// has always done.
+#[cfg(feature = "unstable_mem")]
+pub struct Move(MoveBuf);
+
+#[cfg(feature = "unstable_mem")]
+enum MoveBuf {
+    Region(crate::MappedRegion<u8>),
+    Pages(crate::HeapPageBuf),
+}
+
+#[cfg(feature = "unstable_mem")]
+impl private::Sealed for Move {
+    const INVOKE_TYPE: InvokeType = InvokeType::Move;
+
+    fn registers(&self, args: [usize; 4]) -> [usize; 4] {
+        let data = match &self.0 {
+            MoveBuf::Region(data) => &data[..],
+            MoveBuf::Pages(data) => data.as_bytes(),
+        };
+        validate::check_move("move", data.as_ptr(), data.len());
+        memory_registers(data, args)
+    }
+
+    fn sent(self, result: Result<[usize; 5], Error>) -> Result<[usize; 5], Error> {
+        // The memory now belongs to the server, or is in an unknown state.
+        core::mem::forget(self);
+        result
+    }
+}
+
+#[cfg(feature = "unstable_mem")]
+impl MessageKind for Move {
+    type Output = ();
+
+    fn output(_result: [usize; 5]) {}
+}
+
+/// A message that is ready to be sent to a server.
+#[must_use = "a message does nothing until it is sent"]
+pub struct Message<K: MessageKind> {
+    kind: K,
+    opcode: usize,
+    args: [usize; 4],
+}
+
+impl Message<Scalar> {
+    /// Creates a scalar message, which does not wait for a response.
+    pub fn scalar() -> Self {
+        Self::with_kind(Scalar)
+    }
+
+    /// Sets all four arguments.
+    pub fn scalar_args(mut self, args: [usize; 4]) -> Self {
+        self.args = args;
+        self
+    }
+}
+
+impl Message<BlockingScalar> {
+    /// Creates a scalar message that waits for the server to respond with five
+    /// values.
+    pub fn blocking_scalar() -> Self {
+        Self::with_kind(BlockingScalar)
+    }
+
+    /// Sets all four arguments.
+    pub fn scalar_args(mut self, args: [usize; 4]) -> Self {
+        self.args = args;
+        self
+    }
+}
+
+impl<'a> Message<Lend<'a>> {
+    /// Creates a message that lends `data` to the server. `data` must be
+    /// page-aligned and a whole number of pages long.
+    pub fn lend(data: &'a [u8]) -> Self {
+        Self::with_kind(Lend(data))
+    }
+
+    /// Creates a message that lends a page-aligned buffer to the server.
+    pub fn lend_pages(data: &'a impl PageAligned) -> Self {
+        Self::lend(data.as_bytes())
+    }
+}
+
+impl<'a> Message<LendMut<'a>> {
+    /// Creates a message that mutably lends `data` to the server. `data` must
+    /// be page-aligned and a whole number of pages long.
+    pub fn lend_mut(data: &'a mut [u8]) -> Self {
+        Self::with_kind(LendMut(data))
+    }
+
+    /// Creates a message that mutably lends a page-aligned buffer to the
+    /// server.
+    pub fn lend_pages_mut(data: &'a mut impl PageAligned) -> Self {
+        Self::lend_mut(data.as_bytes_mut())
+    }
+}
+
+#[cfg(feature = "unstable_mem")]
+impl Message<Move> {
+    /// Creates a message that moves a region from `map_memory` to the server.
+    pub fn r#move(data: crate::MappedRegion<u8>) -> Self {
+        Self::with_kind(Move(MoveBuf::Region(data)))
+    }
+
+    /// Creates a message that moves a page-aligned buffer to the server.
+    pub fn move_pages(data: crate::HeapPageBuf) -> Self {
+        Self::with_kind(Move(MoveBuf::Pages(data)))
+    }
+}
+
+impl<K: MessageKind> Message<K> {
+    fn with_kind(kind: K) -> Self {
+        Message {
+            kind,
+            opcode: 0,
+            args: [0; 4],
+        }
+    }
+
+    /// Sets the opcode, which defaults to `0`.
+    pub fn opcode(mut self, opcode: usize) -> Self {
+        self.opcode = opcode;
+        self
+    }
+
+    /// Sets the first two arguments, which default to `0`. These are the only
+    /// arguments sent alongside a buffer.
+    pub fn args(mut self, arg1: usize, arg2: usize) -> Self {
+        self.args[0] = arg1;
+        self.args[1] = arg2;
+        self
+    }
+
+    /// Sends the message to `connection`, waiting for room in the server's
+    /// mailbox as described by `mode`.
+    pub fn send(self, connection: Connection, mode: SendMode) -> Result<K::Output, Error> {
+        let _lend = match self.kind.lent() {
+            Some((data, false)) => validate::check_lend("lend", data.as_ptr(), data.len(), false),
+            Some((data, true)) => validate::check_lend("lend_mut", data.as_ptr(), data.len(), true),
+            None => LendGuard::none(),
+        };
+        let registers = self.kind.registers(self.args);
+        let send = |syscall_number| {
+            unsafe {
+                syscall(
+                    syscall_number,
+                    connection.0 as _,
+                    K::INVOKE_TYPE as _,
+                    self.opcode,
+                    registers[0],
+                    registers[1],
+                    registers[2],
+                    registers[3],
+                )
+            }
+            .map(|result| [result.1, result.2, result.3, result.4, result.5])
+        };
+
+        let result = match mode {
+            SendMode::Block => send(Syscall::SendMessage),
+            SendMode::Try => send(Syscall::TrySendMessage),
+            SendMode::Timeout(timeout) => RetryPolicy::new()
+                .deadline(timeout)
+                .retry(|| send(Syscall::TrySendMessage)),
+            SendMode::Retry(policy) => policy.retry(|| send(Syscall::TrySendMessage)),
+        };
+        self.kind.sent(result).map(K::output)
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::mock::QUEUE_DEPTH;
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn message_builder_send_modes() {
+        let sid = create_server_with_address(address("mock-builder")).unwrap();
+        let cid = connect(address("mock-builder")).unwrap();
+        for n in 0..QUEUE_DEPTH {
+            Message::scalar()
+                .opcode(1)
+                .args(n, 0)
+                .send(cid, SendMode::Try)
+                .unwrap();
+        }
+        assert!(matches!(
+            Message::scalar().send(cid, SendMode::Try),
+            Err(Error::ServerQueueFull)
+        ));
+        assert!(matches!(
+            Message::scalar().send(cid, SendMode::Timeout(Duration::from_millis(20))),
+            Err(Error::Timeout)
+        ));
+
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for n in 0..QUEUE_DEPTH {
+                let envelope = receive_message(sid).unwrap();
+                assert_eq!(envelope.scalar_args().unwrap(), [n, 0, 0, 0]);
+            }
+            let mut envelope = receive_message(sid).unwrap();
+            assert_eq!(envelope.opcode(), 2);
+            envelope.buf_mut().unwrap()[0] = 1;
+            envelope.return_memory(3, 4).unwrap();
+        });
+        let mut buf = PageBuf::<1>::new();
+        let result = Message::lend_pages_mut(&mut buf)
+            .opcode(2)
+            .send(cid, SendMode::Timeout(Duration::from_secs(5)));
+        assert_eq!(result.unwrap(), (3, 4));
+        assert_eq!(buf[0], 1);
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
+    }
+}
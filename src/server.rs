@@ -0,0 +1,309 @@
+//! A minimal framework for writing Xous servers. A [Server] owns a [ServerId],
+//! maps opcodes to handler functions, and runs the receive loop, answering
+//! each message according to how it was sent.
+//!
+//! Handlers mirror the client-side calls that invoke them:
+//!
+//! * `scalar` messages are passed to a [Handler::Scalar]
+//! * `blocking_scalar` messages are passed to a [Handler::BlockingScalar], whose
+//!   return value becomes the result of the client's call
+//! * `lend` and `lend_mut` messages are passed to a [Handler::Lend] or
+//!   [Handler::LendMut], whose return value becomes the `(offset, valid)`
+//!   pair returned to the client
+//! * `move` messages are passed to a [Handler::Move], after which the memory
+//!   is released
+//!
+//! Messages with an opcode that has no handler, or that were sent in a way
+//! that does not match the registered handler, are rejected. Blocking messages
+//! are answered as though a syscall had failed with `Error::InvalidArgument`:
+//! scalars are returned as `[1, Error::InvalidArgument as usize, 0, 0, 0]`, and
+//! memory is returned with an `offset` of `1` and a `valid` of
+//! `Error::InvalidArgument as usize`.
+
+use crate::{
+    Error, InvokeType, MessageBody, MessageEnvelope, ServerAddress, ServerId, Syscall,
+    SyscallResult, syscall,
+};
+
+/// A function that handles a single opcode, operating on server state `S`.
+pub enum Handler<S> {
+    /// Handles messages sent with `scalar`.
+    Scalar(fn(&mut S, [usize; 4])),
+    /// Handles messages sent with `blocking_scalar`.
+    BlockingScalar(fn(&mut S, [usize; 4]) -> [usize; 5]),
+    /// Handles buffers sent with `lend`.
+    Lend(fn(&mut S, &[u8], usize, usize) -> (usize, usize)),
+    /// Handles buffers sent with `lend_mut`.
+    LendMut(fn(&mut S, &mut [u8], usize, usize) -> (usize, usize)),
+    /// Handles buffers sent with `move`.
+    Move(fn(&mut S, &mut [u8], usize, usize)),
+}
+
+impl<S> Handler<S> {
+    fn invoke_type(&self) -> InvokeType {
+        match self {
+            Handler::Scalar(_) => InvokeType::Scalar,
+            Handler::BlockingScalar(_) => InvokeType::BlockingScalar,
+            Handler::Lend(_) => InvokeType::Lend,
+            Handler::LendMut(_) => InvokeType::LendMut,
+            Handler::Move(_) => InvokeType::Move,
+        }
+    }
+}
+
+/// A server that dispatches messages to handlers by opcode. Up to `N`
+/// opcodes may be registered.
+///
+/// The underlying server is destroyed when this is dropped.
+pub struct Server<S, const N: usize = 16> {
+    sid: ServerId,
+    handlers: [Option<(usize, Handler<S>)>; N],
+}
+
+impl<S, const N: usize> Server<S, N> {
+    /// Takes ownership of an existing server.
+    ///
+    /// # Safety
+    ///
+    /// `sid` must be a server created by this process that nothing else
+    /// receives messages from or destroys, since it is destroyed when this is
+    /// dropped. Use [Server::create] or [Server::create_with_address] to create
+    /// a new one instead.
+    pub unsafe fn new(sid: ServerId) -> Self {
+        Server {
+            sid,
+            handlers: [const { None }; N],
+        }
+    }
+
+    /// Creates a new server with an address chosen by the kernel.
+    pub fn create() -> Result<Self, Error> {
+        // The server was just created, so nothing else uses it.
+        Ok(unsafe { Self::new(crate::create_server()?) })
+    }
+
+    /// Creates a new server at the specified `address`.
+    pub fn create_with_address(address: ServerAddress) -> Result<Self, Error> {
+        Ok(unsafe { Self::new(crate::create_server_with_address(address)?) })
+    }
+
+    /// The ID of the underlying server.
+    pub fn sid(&self) -> ServerId {
+        self.sid
+    }
+
+    /// Registers `handler` for `opcode`, replacing any existing handler for
+    /// that opcode.
+    ///
+    /// Returns `Error::OutOfMemory` if `N` handlers are already registered.
+    pub fn register(&mut self, opcode: usize, handler: Handler<S>) -> Result<(), Error> {
+        let index = self
+            .handlers
+            .iter()
+            .position(|slot| matches!(slot, Some((op, _)) if *op == opcode))
+            .or_else(|| self.handlers.iter().position(|slot| slot.is_none()))
+            .ok_or(Error::OutOfMemory)?;
+        self.handlers[index] = Some((opcode, handler));
+        Ok(())
+    }
+
+    /// Registers a handler for messages sent with `scalar`.
+    pub fn on_scalar(&mut self, opcode: usize, f: fn(&mut S, [usize; 4])) -> Result<(), Error> {
+        self.register(opcode, Handler::Scalar(f))
+    }
+
+    /// Registers a handler for messages sent with `blocking_scalar`.
+    pub fn on_blocking_scalar(
+        &mut self,
+        opcode: usize,
+        f: fn(&mut S, [usize; 4]) -> [usize; 5],
+    ) -> Result<(), Error> {
+        self.register(opcode, Handler::BlockingScalar(f))
+    }
+
+    /// Registers a handler for buffers sent with `lend`.
+    pub fn on_lend(
+        &mut self,
+        opcode: usize,
+        f: fn(&mut S, &[u8], usize, usize) -> (usize, usize),
+    ) -> Result<(), Error> {
+        self.register(opcode, Handler::Lend(f))
+    }
+
+    /// Registers a handler for buffers sent with `lend_mut`.
+    pub fn on_lend_mut(
+        &mut self,
+        opcode: usize,
+        f: fn(&mut S, &mut [u8], usize, usize) -> (usize, usize),
+    ) -> Result<(), Error> {
+        self.register(opcode, Handler::LendMut(f))
+    }
+
+    /// Registers a handler for buffers sent with `move`.
+    pub fn on_move(
+        &mut self,
+        opcode: usize,
+        f: fn(&mut S, &mut [u8], usize, usize),
+    ) -> Result<(), Error> {
+        self.register(opcode, Handler::Move(f))
+    }
+
+    /// Receives messages and dispatches them to their handlers forever.
+    ///
+    /// This only returns if receiving a message fails. Errors from answering a
+    /// message are ignored, since they only mean that its sender can no longer
+    /// be reached, and the server carries on with the next message.
+    pub fn run(&self, state: &mut S) -> Error {
+        loop {
+            match crate::receive_message(self.sid) {
+                Ok(envelope) => {
+                    let _ = self.dispatch(state, envelope);
+                }
+                Err(e) => return e,
+            }
+        }
+    }
+
+    /// Waits for a single message and dispatches it to its handler.
+    pub fn handle_one(&self, state: &mut S) -> Result<(), Error> {
+        let envelope = crate::receive_message(self.sid)?;
+        self.dispatch(state, envelope)
+    }
+
+    /// Passes a message that has already been received to its handler,
+    /// and sends the response.
+    pub fn dispatch(&self, state: &mut S, mut envelope: MessageEnvelope) -> Result<(), Error> {
+        let Some(handler) = self
+            .handlers
+            .iter()
+            .flatten()
+            .find(|(op, _)| *op == envelope.opcode())
+            .map(|(_, handler)| handler)
+            .filter(|handler| handler.invoke_type() == envelope.invoke_type())
+        else {
+            return reject(envelope);
+        };
+
+        let (offset, valid) = envelope
+            .memory()
+            .map(|mem| (mem.offset, mem.valid))
+            .unwrap_or_default();
+        match handler {
+            Handler::Scalar(f) => {
+                f(state, envelope.scalar_args().unwrap_or_default());
+                Ok(())
+            }
+            Handler::BlockingScalar(f) => {
+                let result = f(state, envelope.scalar_args().unwrap_or_default());
+                envelope.return_scalar(result)
+            }
+            Handler::Lend(f) => {
+                let (offset, valid) = f(state, envelope.buf().unwrap_or_default(), offset, valid);
+                envelope.return_memory(offset, valid)
+            }
+            Handler::LendMut(f) => {
+                let (offset, valid) =
+                    f(state, envelope.buf_mut().unwrap_or_default(), offset, valid);
+                envelope.return_memory(offset, valid)
+            }
+            Handler::Move(f) => {
+                f(state, envelope.buf_mut().unwrap_or_default(), offset, valid);
+                release(envelope)
+            }
+        }
+    }
+}
+
+impl<S, const N: usize> Drop for Server<S, N> {
+    fn drop(&mut self) {
+        let _ = crate::destroy_server(self.sid);
+    }
+}
+
+/// Answers a message that has no matching handler.
+fn reject(envelope: MessageEnvelope) -> Result<(), Error> {
+    let error = Error::InvalidArgument as usize;
+    match envelope.invoke_type() {
+        InvokeType::BlockingScalar => {
+            envelope.return_scalar([SyscallResult::Error as usize, error, 0, 0, 0])
+        }
+        InvokeType::Lend | InvokeType::LendMut => {
+            envelope.return_memory(SyscallResult::Error as usize, error)
+        }
+        InvokeType::Move => release(envelope),
+        InvokeType::Scalar => Ok(()),
+    }
+}
+
+/// Unmaps memory that was moved into this process.
+fn release(envelope: MessageEnvelope) -> Result<(), Error> {
+    if let (_, MessageBody::Memory(mem)) = envelope.defer()
+        && !mem.is_empty()
+    {
+        unsafe { syscall(Syscall::UnmapMemory, mem.addr, mem.len, 0, 0, 0, 0, 0)? };
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::thread;
+
+    use super::Server;
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn server_dispatch() {
+        let sid = create_server_with_address(address("mock-dispatch")).unwrap();
+        let server = thread::spawn(move || {
+            let mut server: Server<usize> = unsafe { Server::new(sid) };
+            server.on_scalar(1, |count, [n, ..]| *count += n).unwrap();
+            server
+                .on_blocking_scalar(2, |count, _| [*count, 0, 0, 0, 0])
+                .unwrap();
+            server
+                .on_lend_mut(3, |_, buf, offset, valid| {
+                    buf[0] = b'!';
+                    (offset, valid * 2)
+                })
+                .unwrap();
+            let mut count = 0;
+            for _ in 0..6 {
+                server.handle_one(&mut count).unwrap();
+            }
+            count
+        });
+        let cid = connect(address("mock-dispatch")).unwrap();
+        scalar(cid, [1, 20, 0, 0, 0]).unwrap();
+        scalar(cid, [1, 22, 0, 0, 0]).unwrap();
+        assert_eq!(blocking_scalar(cid, [2, 0, 0, 0, 0]).unwrap()[0], 42);
+
+        let mut buf = PageBuf::<1>::new();
+        assert_eq!(lend_pages_mut(cid, 3, &mut buf, 1, 4).unwrap(), (1, 8));
+        assert_eq!(buf[0], b'!');
+
+        // Unknown opcodes, and known opcodes sent the wrong way, are rejected.
+        let error = [
+            SyscallResult::Error as usize,
+            Error::InvalidArgument as usize,
+            0,
+            0,
+            0,
+        ];
+        assert_eq!(blocking_scalar(cid, [99, 0, 0, 0, 0]).unwrap(), error);
+        assert_eq!(
+            lend_pages(cid, 2, &buf, 0, 0).unwrap(),
+            (
+                SyscallResult::Error as usize,
+                Error::InvalidArgument as usize
+            )
+        );
+
+        // The server is destroyed when it is dropped.
+        assert_eq!(server.join().unwrap(), 42);
+        assert!(matches!(try_connect(address("mock-dispatch")), Ok(None)));
+    }
+}
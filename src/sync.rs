@@ -0,0 +1,642 @@
+//! Blocking synchronization primitives. Threads that need to wait are parked
+//! in the ticktimer server rather than spinning, using the same protocol as
+//! the Rust standard library on Xous (`library/std/src/sys/sync/mutex/xous.rs`
+//! and `condvar/xous.rs`), so these may be mixed with `std` types in the same
+//! process.
+//!
+//! The ticktimer identifies each lock by its address, so a [Mutex] or
+//! [Condvar] must not be moved while another thread is using it. Borrowing
+//! rules already ensure this while a lock is held or waited on. Once a thread
+//! has had to wait for one, it should also stay where it is until it is
+//! dropped, which is when the ticktimer is told to forget it; see [Mutex].
+//! For [wait] and [notify], keeping the address fixed is up to the caller.
+//!
+//! Unlike `std`, locks are not poisoned when a thread panics while holding
+//! them.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::{Error, do_yield, ticktimer};
+
+/// The longest a futex `wait` sleeps before checking the value again. The
+/// ticktimer only wakes threads that are already waiting, so a `notify` that
+/// arrives just before a waiter is parked would otherwise be lost.
+const FUTEX_RECHECK_MS: usize = 20;
+
+/// Converts a timeout to the milliseconds expected by the ticktimer, rounding
+/// up so that a short timeout does not become `0`, which waits forever.
+fn timeout_ms(timeout: Duration) -> usize {
+    let ms = timeout.as_nanos().div_ceil(1_000_000).max(1);
+    usize::try_from(ms).unwrap_or(usize::MAX)
+}
+
+fn expect_ticktimer<T>(result: Result<T, Error>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => panic!("ticktimer request failed: {}", e),
+    }
+}
+
+/// The lock behind [Mutex], without any data.
+///
+/// `locked` counts the owner plus every thread waiting for it. A thread that
+/// finds the lock taken parks in the ticktimer, and the owner wakes one such
+/// thread when it unlocks.
+struct RawMutex {
+    locked: AtomicUsize,
+    contended: AtomicBool,
+}
+
+impl RawMutex {
+    const fn new() -> Self {
+        RawMutex {
+            locked: AtomicUsize::new(0),
+            contended: AtomicBool::new(false),
+        }
+    }
+
+    fn cookie(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn lock(&self) {
+        // Locks are usually held briefly, so try a few times before involving
+        // the ticktimer.
+        for _ in 0..3 {
+            if self.try_lock() {
+                return;
+            }
+            do_yield();
+        }
+
+        // If the lock was released in the meantime, it is now ours.
+        if self.locked.fetch_add(1, Ordering::Acquire) == 0 {
+            return;
+        }
+        self.contended.store(true, Ordering::Relaxed);
+        expect_ticktimer(ticktimer::lock_mutex(self.cookie()));
+    }
+
+    /// # Safety
+    ///
+    /// The calling thread must hold the lock.
+    unsafe fn unlock(&self) {
+        let previous = self.locked.fetch_sub(1, Ordering::Release);
+        if previous == 1 {
+            // Nobody was waiting.
+            return;
+        }
+        assert!(previous != 0, "mutex lock count underflowed");
+        expect_ticktimer(ticktimer::unlock_mutex(self.cookie()));
+    }
+}
+
+impl Drop for RawMutex {
+    fn drop(&mut self) {
+        if self.contended.load(Ordering::Relaxed) {
+            let _ = ticktimer::free_mutex(self.cookie());
+        }
+    }
+}
+
+/// A mutual exclusion lock protecting a value of type `T`.
+///
+/// The ticktimer knows the mutex by its address. Moving it is harmless until
+/// a thread has had to wait for it, but after that it should not be moved
+/// again, as with `std::sync::Mutex` on Xous: the ticktimer is told to forget
+/// the address it has when it is dropped, so the state kept for its old
+/// address is leaked. Put it in a `static`, a `Box` or an `Arc` if it may be
+/// moved.
+pub struct Mutex<T: ?Sized> {
+    raw: RawMutex,
+    data: UnsafeCell<T>,
+}
+
+// Safety: the lock ensures that only one thread accesses `data` at a time.
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex.
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            raw: RawMutex::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the mutex, returning the value.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Blocks until the lock is available, then takes it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ticktimer server cannot be reached.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.raw.lock();
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Takes the lock if it is available, without blocking.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.raw.try_lock().then(|| MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Returns the value. No locking is needed, since the mutex is borrowed
+    /// mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Gives access to the value in a [Mutex], and unlocks it when dropped.
+///
+/// As with `std`, the guard cannot be sent to another thread, so the mutex is
+/// always unlocked by the thread that locked it.
+#[must_use = "the mutex is unlocked as soon as the guard is dropped"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+// Safety: the guard only gives out references to `T`.
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // The lock is held for as long as the guard exists.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // The lock is held for as long as the guard exists.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // The guard is only created once the lock is taken.
+        unsafe { self.mutex.raw.unlock() }
+    }
+}
+
+/// Returned by [Condvar::wait_timeout] to report whether the wait timed out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Returns `true` if the wait ended because the timeout passed.
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A condition variable, for waiting until another thread signals that
+/// something protected by a [Mutex] has changed.
+///
+/// As with `std`, waits may end spuriously, so the condition should be
+/// checked again in a loop, or with [Condvar::wait_while].
+pub struct Condvar {
+    /// Threads that are waiting and have not yet been chosen to be notified.
+    waiting: AtomicUsize,
+    /// Threads that timed out after being chosen to be notified, which a
+    /// notifier should stop trying to wake.
+    timed_out: AtomicUsize,
+    used: AtomicBool,
+}
+
+impl Condvar {
+    /// Creates a condition variable with no waiting threads.
+    pub const fn new() -> Self {
+        Condvar {
+            waiting: AtomicUsize::new(0),
+            timed_out: AtomicUsize::new(0),
+            used: AtomicBool::new(false),
+        }
+    }
+
+    fn cookie(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Unlocks the mutex and blocks until notified, or until `ms` has passed
+    /// if it is not `0`, then locks the mutex again. Returns `true` if the
+    /// thread was notified.
+    fn wait_ms<T: ?Sized>(&self, guard: &MutexGuard<'_, T>, ms: usize) -> bool {
+        self.used.store(true, Ordering::Relaxed);
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let raw = &guard.mutex.raw;
+        // The guard shows that this thread holds the lock, and it is taken
+        // again before the guard is returned to the caller.
+        unsafe { raw.unlock() };
+        let notified = expect_ticktimer(ticktimer::wait_for_condition(self.cookie(), ms));
+        if !notified {
+            // Withdraw from `waiting`, unless a notifier has already counted
+            // this thread, in which case tell it to give up on this thread.
+            let withdrawn = self
+                .waiting
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok();
+            if !withdrawn {
+                self.timed_out.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        raw.lock();
+        notified
+    }
+
+    /// Unlocks the mutex and blocks until this condition variable is
+    /// notified, then locks the mutex again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_ms(&guard, 0);
+        guard
+    }
+
+    /// Waits until `condition` returns `false`, checking it each time this
+    /// condition variable is notified.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Like [Condvar::wait], but gives up once `timeout` has passed. The
+    /// timeout is rounded up to whole milliseconds.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let notified = self.wait_ms(&guard, timeout_ms(timeout));
+        (guard, WaitTimeoutResult(!notified))
+    }
+
+    /// Wakes up one waiting thread, if there is one.
+    pub fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    /// Wakes up every waiting thread.
+    pub fn notify_all(&self) {
+        self.notify(usize::MAX);
+    }
+
+    fn notify(&self, count: usize) {
+        let Ok(waiting) =
+            self.waiting
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |waiting| {
+                    (waiting > 0).then(|| waiting - waiting.min(count))
+                })
+        else {
+            return;
+        };
+        let mut remaining = waiting.min(count);
+
+        // A thread counts itself as waiting before it reaches the ticktimer,
+        // so keep notifying until every thread that was counted is woken.
+        while remaining > 0 {
+            let gave_up = self.timed_out.swap(0, Ordering::Relaxed);
+            remaining = remaining.saturating_sub(gave_up);
+            if remaining == 0 {
+                break;
+            }
+            remaining -= expect_ticktimer(ticktimer::notify_condition(self.cookie(), remaining));
+            if remaining > 0 {
+                do_yield();
+            }
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Condvar {
+    fn drop(&mut self) {
+        if self.used.load(Ordering::Relaxed) {
+            let _ = ticktimer::free_condition(self.cookie());
+        }
+    }
+}
+
+struct RwState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+/// A reader-writer lock, allowing either any number of readers or a single
+/// writer at a time. Waiting writers take priority over new readers, so
+/// writers are not starved.
+pub struct RwLock<T: ?Sized> {
+    state: Mutex<RwState>,
+    changed: Condvar,
+    data: UnsafeCell<T>,
+}
+
+// Safety: the lock ensures that `data` is either shared or exclusively
+// borrowed, as with `std::sync::RwLock`.
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    /// Creates an unlocked lock.
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: Mutex::new(RwState {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+            }),
+            changed: Condvar::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consumes the lock, returning the value.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Blocks until there is no writer, then takes a shared lock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let mut state = self.changed.wait_while(self.state.lock(), |state| {
+            state.writer || state.waiting_writers > 0
+        });
+        state.readers += 1;
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Takes a shared lock if there is no writer, without blocking.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.waiting_writers > 0 {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    /// Blocks until there are no readers or writers, then takes an exclusive
+    /// lock.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut state = self.state.lock();
+        state.waiting_writers += 1;
+        let mut state = self
+            .changed
+            .wait_while(state, |state| state.writer || state.readers > 0);
+        state.waiting_writers -= 1;
+        state.writer = true;
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Takes an exclusive lock if there are no readers or writers, without
+    /// blocking.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    /// Returns the value. No locking is needed, since the lock is borrowed
+    /// mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// Gives shared access to the value in a [RwLock], and releases it when
+/// dropped.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // No writer exists while a read guard does.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            self.lock.changed.notify_all();
+        }
+    }
+}
+
+/// Gives exclusive access to the value in a [RwLock], and releases it when
+/// dropped.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // The write guard is the only way to reach the value.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // The write guard is the only way to reach the value.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.writer = false;
+        self.lock.changed.notify_all();
+    }
+}
+
+/// Blocks while `futex` holds `expected`, until [notify] is called for it or
+/// `timeout` passes. Returns `false` if the timeout passed, and `true`
+/// otherwise, including when `futex` did not hold `expected` to begin with.
+///
+/// Like a Linux futex, this may return spuriously, so the caller should check
+/// the value again. Because the ticktimer only wakes threads that are already
+/// waiting, the value is also checked at least every 20ms, so a notification
+/// that races with the start of a wait is never missed for longer than that.
+pub fn wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let cookie = futex as *const AtomicU32 as usize;
+    let deadline = match timeout {
+        Some(timeout) => {
+            let start = expect_ticktimer(ticktimer::elapsed_ms());
+            Some(start.saturating_add(timeout_ms(timeout) as u64))
+        }
+        None => None,
+    };
+    while futex.load(Ordering::Acquire) == expected {
+        let mut ms = FUTEX_RECHECK_MS;
+        if let Some(deadline) = deadline {
+            let now = expect_ticktimer(ticktimer::elapsed_ms());
+            if now >= deadline {
+                return false;
+            }
+            ms = ms.min((deadline - now) as usize);
+        }
+        if expect_ticktimer(ticktimer::wait_for_condition(cookie, ms)) {
+            return true;
+        }
+    }
+    true
+}
+
+/// Wakes up to `count` threads blocked in [wait] on `futex`, returning how
+/// many were woken.
+pub fn notify(futex: &AtomicU32, count: usize) -> usize {
+    let cookie = futex as *const AtomicU32 as usize;
+    expect_ticktimer(ticktimer::notify_condition(cookie, count))
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn mutex_and_condvar() {
+        let counter = Mutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        let mut count = counter.lock();
+                        let value = *count;
+                        thread::yield_now();
+                        *count = value + 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.into_inner(), 800);
+
+        let ready = Mutex::new(false);
+        let changed = Condvar::new();
+        let guard = ready.lock();
+        assert!(ready.try_lock().is_none());
+        let (guard, result) = changed.wait_timeout(guard, Duration::from_millis(5));
+        assert!(result.timed_out());
+        drop(guard);
+
+        thread::scope(|s| {
+            let waiters: std::vec::Vec<_> = (0..3)
+                .map(|_| s.spawn(|| *changed.wait_while(ready.lock(), |ready| !*ready)))
+                .collect();
+            thread::sleep(Duration::from_millis(10));
+            *ready.lock() = true;
+            changed.notify_all();
+            for waiter in waiters {
+                assert!(waiter.join().unwrap());
+            }
+        });
+
+        // A notification with nobody waiting is not remembered.
+        changed.notify_one();
+        let (_guard, result) = changed.wait_timeout(ready.lock(), Duration::from_millis(5));
+        assert!(result.timed_out());
+    }
+
+    #[test]
+    fn rwlock_and_futex() {
+        let lock = RwLock::new(1);
+        {
+            let first = lock.read();
+            let second = lock.try_read().unwrap();
+            assert_eq!(*first + *second, 2);
+            assert!(lock.try_write().is_none());
+        }
+        thread::scope(|s| {
+            let reader = lock.read();
+            let writer = s.spawn(|| *lock.write() += 1);
+            thread::sleep(Duration::from_millis(10));
+            // The waiting writer holds off new readers.
+            assert!(lock.try_read().is_none());
+            assert_eq!(*reader, 1);
+            drop(reader);
+            writer.join().unwrap();
+        });
+        assert_eq!(*lock.read(), 2);
+
+        let futex = AtomicU32::new(0);
+        assert!(wait(&futex, 1, None));
+        assert!(!wait(&futex, 0, Some(Duration::from_millis(5))));
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                while futex.load(Ordering::Acquire) == 0 {
+                    wait(&futex, 0, None);
+                }
+            });
+            thread::sleep(Duration::from_millis(10));
+            futex.store(1, Ordering::Release);
+            notify(&futex, 1);
+            waiter.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,301 @@
+//! Spawning threads that run a closure, in the style of `std::thread`.
+//!
+//! Each thread gets a stack from the heap, which is released once the thread
+//! has been joined. With the `unstable_mem` feature, the stack is a
+//! [GuardedStack](crate::GuardedStack) mapped from the kernel instead, which
+//! may also be given a canary. A thread whose [JoinHandle] is dropped without
+//! being joined keeps running, and its stack is never released.
+//!
+//! ```text
+//! let handle = thread::Builder::new()
+//!     .name("worker")
+//!     .stack_size(64 * 1024)
+//!     .spawn(|| compute())?;
+//! let result = handle.join()?;
+//! ```
+
+extern crate alloc;
+#[cfg(any(feature = "mock", feature = "hosted"))]
+extern crate std;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+
+#[cfg(feature = "unstable_mem")]
+use crate::GuardedStack;
+use crate::{Error, JoinError, PAGE_SIZE, ThreadId};
+
+/// The stack size used when none is given, matching the Rust standard library
+/// on Xous.
+pub const DEFAULT_STACK_SIZE: usize = 128 * 1024;
+
+/// Where a thread leaves the value returned by its closure, for the thread
+/// that joins it.
+struct Packet<T> {
+    result: UnsafeCell<Option<T>>,
+}
+
+// Safety: the spawned thread writes `result` before it exits, and it is only
+// read after `JoinThread` has confirmed that the thread has exited.
+unsafe impl<T: Send> Sync for Packet<T> {}
+
+type Main = Box<dyn FnOnce() + Send>;
+
+/// The memory a spawned thread runs on.
+#[cfg(feature = "unstable_mem")]
+type Stack = GuardedStack;
+#[cfg(not(feature = "unstable_mem"))]
+type Stack = Box<[u8]>;
+
+/// Configures a thread before it is spawned.
+#[derive(Debug, Clone)]
+#[must_use = "a builder does nothing until a thread is spawned"]
+pub struct Builder {
+    name: Option<String>,
+    stack_size: usize,
+    #[cfg(feature = "unstable_mem")]
+    stack_canary: bool,
+}
+
+impl Builder {
+    /// Creates a builder for an unnamed thread with the default stack size.
+    pub fn new() -> Self {
+        Builder {
+            name: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            #[cfg(feature = "unstable_mem")]
+            stack_canary: false,
+        }
+    }
+
+    /// Names the thread. The kernel has no notion of thread names, so this is
+    /// only available from [JoinHandle::name].
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the size of the thread's stack, which is rounded up to a whole
+    /// number of pages.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Writes a canary to the bottom of the thread's stack, which is checked
+    /// when it is joined. See [GuardedStack](crate::GuardedStack).
+    #[cfg(feature = "unstable_mem")]
+    pub fn stack_canary(mut self, enabled: bool) -> Self {
+        self.stack_canary = enabled;
+        self
+    }
+
+    /// Starts a thread that runs `f`, returning a handle that can be used to
+    /// wait for it and collect its result.
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, Error>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut stack = self.new_stack()?;
+        let packet = Arc::new(Packet {
+            result: UnsafeCell::new(None),
+        });
+
+        let their_packet = packet.clone();
+        let main: Main = Box::new(move || {
+            let value = f();
+            // Nothing else reads the packet until this thread has exited.
+            unsafe { *their_packet.result.get() = Some(value) };
+        });
+        let main = Box::into_raw(Box::new(main));
+
+        let result = unsafe {
+            crate::threads::create_thread_raw(
+                thread_start as *const () as usize,
+                stack.as_mut(),
+                [main.expose_provenance(), 0, 0, 0],
+            )
+        };
+        let thread_id = match result {
+            Ok(thread_id) => thread_id,
+            Err(e) => {
+                // The thread was never started, so the closure is still ours.
+                drop(unsafe { Box::from_raw(main) });
+                return Err(e);
+            }
+        };
+
+        Ok(JoinHandle {
+            thread_id,
+            name: self.name,
+            stack: Some(stack),
+            packet,
+        })
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    fn new_stack(&self) -> Result<Stack, Error> {
+        let stack = GuardedStack::new(self.stack_size.div_ceil(PAGE_SIZE).max(1))?;
+        Ok(if self.stack_canary {
+            stack.with_canary()
+        } else {
+            stack
+        })
+    }
+
+    #[cfg(not(feature = "unstable_mem"))]
+    fn new_stack(&self) -> Result<Stack, Error> {
+        let size = self
+            .stack_size
+            .max(1)
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(Error::OutOfMemory)?;
+        Ok(alloc::vec![0; size].into_boxed_slice())
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Starts a thread that runs `f` with the default settings.
+///
+/// # Panics
+///
+/// Panics if the thread cannot be created. Use [Builder::spawn] to handle
+/// the error instead.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match Builder::new().spawn(f) {
+        Ok(handle) => handle,
+        Err(e) => panic!("failed to spawn thread: {}", e),
+    }
+}
+
+/// The entry point of every spawned thread. `main` is a `Box<Main>` that was
+/// turned into an address by `Builder::spawn`.
+extern "C" fn thread_start(main: usize, _: usize, _: usize, _: usize) -> usize {
+    let main = unsafe { Box::from_raw(core::ptr::with_exposed_provenance_mut::<Main>(main)) };
+    run(*main);
+    0
+}
+
+/// On the host, a panic must not unwind out of `thread_start`, which would
+/// abort the whole process. It is caught instead, which leaves the packet
+/// empty just as a thread that exits while panicking on Xous would.
+#[cfg(any(feature = "mock", feature = "hosted"))]
+fn run(main: Main) {
+    let _ = std::panic::catch_unwind(core::panic::AssertUnwindSafe(main));
+}
+
+#[cfg(not(any(feature = "mock", feature = "hosted")))]
+fn run(main: Main) {
+    main()
+}
+
+/// An owned permission to wait for a spawned thread and collect its result.
+///
+/// Dropping the handle detaches the thread. It keeps running, but its stack is
+/// leaked since nothing is left to release it once it exits.
+#[must_use = "dropping a JoinHandle detaches the thread and leaks its stack"]
+pub struct JoinHandle<T> {
+    thread_id: ThreadId,
+    name: Option<String>,
+    stack: Option<Stack>,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the kernel's ID for the thread.
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    /// Returns the name given to [Builder::name], if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Waits for the thread to exit, then returns the value returned by its
+    /// closure and releases its stack.
+    ///
+    /// Returns `JoinError::Panicked` if the thread exited without returning
+    /// from its closure, `JoinError::StackOverflow` if the stack has a canary
+    /// and the thread overwrote it, and `JoinError::Kernel` for any error from
+    /// `JoinThread`, in which case the stack is leaked since the thread may
+    /// still be running on it.
+    ///
+    /// On the host, a panic in the thread is caught and reported as
+    /// `JoinError::Panicked`. On Xous, panics do not unwind, so what happens is
+    /// up to the program's panic handler: if it ends only the panicking
+    /// thread, this returns `JoinError::Panicked`, and if it terminates the
+    /// process, nothing is left to join the thread.
+    pub fn join(mut self) -> Result<T, JoinError> {
+        crate::join_thread(self.thread_id)?;
+        // The thread has exited, so this is the only reference to its stack
+        // and the packet.
+        #[cfg(feature = "unstable_mem")]
+        if let Some(stack) = self.stack.take() {
+            stack.check_canary(self.thread_id)?;
+        }
+        drop(self.stack.take());
+        unsafe { (*self.packet.result.get()).take() }.ok_or(JoinError::Panicked)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            // The thread may still be using it.
+            core::mem::forget(stack);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::format;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sync::Mutex;
+
+    #[test]
+    fn spawn_and_join_threads() {
+        let handle = spawn(|| String::from("done"));
+        assert_eq!(handle.name(), None);
+        assert_eq!(handle.join().unwrap(), "done");
+
+        let counter = Arc::new(Mutex::new(0u32));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let counter = counter.clone();
+                Builder::new()
+                    .name(format!("worker-{}", i))
+                    .stack_size(5000)
+                    .spawn(move || {
+                        *counter.lock() += 1;
+                        i * 2
+                    })
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(handles[3].name(), Some("worker-3"));
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, [0, 2, 4, 6]);
+        assert_eq!(*counter.lock(), 4);
+
+        let panicked = spawn(|| -> u32 { panic!("thread failed") });
+        assert!(matches!(panicked.join(), Err(JoinError::Panicked)));
+    }
+}
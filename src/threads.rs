@@ -0,0 +1,441 @@
+//! Creating threads that start at a raw entry point.
+//!
+//! The entry point is called as an
+//! `extern "C" fn(usize, usize, usize, usize) -> usize` with the four
+//! arguments, and its return value is the result of `join_thread`. Threads may
+//! be given a stack from the heap with `create_thread`, which needs the
+//! `alloc` feature, or one that lives for the whole program with
+//! [create_thread_static] or a [ThreadTable], which need no allocator at all.
+//! With the `unstable_mem` feature, [create_thread_guarded] maps a stack from
+//! the kernel with a guard page below it, so that an overflow faults instead
+//! of corrupting other memory.
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{Error, PageAligned, PageBuf, Syscall, SyscallResult, ThreadId, join_thread, syscall};
+
+/// Starts a thread at `start` on the given stack.
+///
+/// # Safety
+///
+/// The stack must remain valid, and must not be used for anything else, until
+/// the thread has exited.
+pub(crate) unsafe fn create_thread_raw(
+    start: usize,
+    stack: &mut [u8],
+    args: [usize; 4],
+) -> Result<ThreadId, Error> {
+    let result = unsafe {
+        syscall(
+            Syscall::CreateThread,
+            start,
+            stack.as_mut_ptr() as usize,
+            stack.len(),
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+        )?
+    };
+    if result.0 != SyscallResult::ThreadId as usize {
+        return Err(Error::InternalError);
+    }
+    Ok(result.1.into())
+}
+
+/// Creates a thread with a given stack and up to four arguments.
+#[cfg(feature = "alloc")]
+pub fn create_thread(
+    start: *mut usize,
+    mut stack: alloc::boxed::Box<[u8]>,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> Result<ThreadId, Error> {
+    let result = unsafe { create_thread_raw(start as usize, &mut stack, [arg0, arg1, arg2, arg3]) };
+
+    // Stack is now owned by the thread
+    core::mem::forget(stack);
+    result
+}
+
+/// Creates a thread with a stack that lives for the rest of the program, and
+/// up to four arguments.
+///
+/// The stack belongs to the thread from then on, so it may not be reused even
+/// once the thread has exited. Use a [ThreadTable] for stacks that are reused.
+pub fn create_thread_static(
+    start: *mut usize,
+    stack: &'static mut [u8],
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> Result<ThreadId, Error> {
+    // The stack is borrowed for the rest of the program, so nothing else can
+    // use it.
+    unsafe { create_thread_raw(start as usize, stack, [arg0, arg1, arg2, arg3]) }
+}
+
+/// A fixed number of thread stacks of `PAGES` pages each, allocated when the
+/// program is built, for programs that have no heap. Place it in a `static`:
+///
+/// ```text
+/// static WORKERS: ThreadTable<2, 4> = ThreadTable::new();
+///
+/// let worker = WORKERS.spawn(worker_main as *mut usize, [0, 0, 0, 0])?;
+/// let result = worker.join()?;
+/// ```
+///
+/// A stack is returned to the table once its thread has been joined, so at
+/// most `THREADS` threads from the table may be running or waiting to be
+/// joined at once.
+pub struct ThreadTable<const THREADS: usize, const PAGES: usize> {
+    stacks: [UnsafeCell<PageBuf<PAGES>>; THREADS],
+    in_use: [AtomicBool; THREADS],
+}
+
+// Safety: each stack is only handed to one thread at a time, as recorded by
+// `in_use`.
+unsafe impl<const THREADS: usize, const PAGES: usize> Sync for ThreadTable<THREADS, PAGES> {}
+
+impl<const THREADS: usize, const PAGES: usize> ThreadTable<THREADS, PAGES> {
+    /// Creates a table with every stack free.
+    pub const fn new() -> Self {
+        ThreadTable {
+            stacks: [const { UnsafeCell::new(PageBuf::new()) }; THREADS],
+            in_use: [const { AtomicBool::new(false) }; THREADS],
+        }
+    }
+
+    /// Creates a thread at `start` with the given arguments, on the first free
+    /// stack in the table.
+    ///
+    /// Returns `Error::ThreadNotAvailable` if every stack is in use.
+    pub fn spawn(
+        &'static self,
+        start: *mut usize,
+        args: [usize; 4],
+    ) -> Result<StaticThread, Error> {
+        for (stack, in_use) in self.stacks.iter().zip(&self.in_use) {
+            if in_use
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            // `in_use` was just claimed, so nothing else has this stack.
+            let stack = unsafe { &mut *stack.get() };
+            return match unsafe { create_thread_raw(start as usize, stack.as_bytes_mut(), args) } {
+                Ok(thread_id) => Ok(StaticThread { thread_id, in_use }),
+                Err(e) => {
+                    in_use.store(false, Ordering::Release);
+                    Err(e)
+                }
+            };
+        }
+        Err(Error::ThreadNotAvailable)
+    }
+}
+
+impl<const THREADS: usize, const PAGES: usize> Default for ThreadTable<THREADS, PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A thread running on a stack from a [ThreadTable].
+///
+/// Dropping this without joining the thread leaves its stack in use for the
+/// rest of the program.
+#[must_use = "the thread's stack is only returned to the table once it is joined"]
+pub struct StaticThread {
+    thread_id: ThreadId,
+    in_use: &'static AtomicBool,
+}
+
+impl StaticThread {
+    /// Returns the kernel's ID for the thread.
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    /// Waits for the thread to exit and returns its exit code, then returns
+    /// its stack to the table.
+    pub fn join(self) -> Result<usize, Error> {
+        let result = join_thread(self.thread_id)?;
+        self.in_use.store(false, Ordering::Release);
+        Ok(result)
+    }
+}
+
+/// An error from joining a thread.
+#[derive(Copy, Clone, Debug)]
+pub enum JoinError {
+    /// The thread overwrote the canary at the bottom of its stack, so its
+    /// stack overflowed even if it did not fault.
+    StackOverflow(ThreadId),
+    /// The thread exited without returning from the closure given to
+    /// `thread::spawn`, which happens when it panics.
+    Panicked,
+    /// Any error from the kernel while waiting for the thread.
+    Kernel(Error),
+}
+
+impl From<Error> for JoinError {
+    fn from(src: Error) -> Self {
+        JoinError::Kernel(src)
+    }
+}
+
+impl core::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            JoinError::StackOverflow(thread_id) => {
+                let thread_id: usize = (*thread_id).into();
+                write!(f, "thread {} overflowed its stack", thread_id)
+            }
+            JoinError::Panicked => write!(f, "the thread panicked"),
+            JoinError::Kernel(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl core::error::Error for JoinError {}
+
+/// The number of bytes at the bottom of a [GuardedStack] that are filled with
+/// a known pattern when it has a canary.
+#[cfg(feature = "unstable_mem")]
+const CANARY_LEN: usize = 64;
+
+#[cfg(feature = "unstable_mem")]
+fn canary_byte(offset: usize) -> u8 {
+    0xa5 ^ offset as u8
+}
+
+/// A thread stack mapped from the kernel, with an inaccessible guard page
+/// below it so that a thread that overflows its stack faults immediately
+/// instead of overwriting other memory.
+///
+/// A stack may also be given a canary, a known pattern just above the guard
+/// page. A function whose stack frame is larger than a page can step over the
+/// guard page without touching it, so the canary is checked when the thread
+/// is joined to catch overflows that did not fault.
+#[cfg(feature = "unstable_mem")]
+pub struct GuardedStack {
+    /// Kept so that the guard page is unmapped along with the stack.
+    _guard: crate::MappedRegion<u8>,
+    stack: crate::MappedRegion<u8>,
+    canary: bool,
+}
+
+#[cfg(feature = "unstable_mem")]
+impl GuardedStack {
+    /// Maps a stack of `pages` pages, plus one more for the guard page.
+    pub fn new(pages: usize) -> Result<Self, Error> {
+        use crate::{MemoryFlags, PAGE_SIZE};
+
+        if pages == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let size = pages
+            .checked_add(1)
+            .and_then(|pages| pages.checked_mul(PAGE_SIZE))
+            .ok_or(Error::OutOfMemory)?;
+        // No virtual address is given, so the kernel picks a new range.
+        let region = unsafe {
+            crate::map_memory::<u8>(
+                None,
+                None,
+                size,
+                MemoryFlags::R | MemoryFlags::W | MemoryFlags::RESERVE,
+            )?
+        };
+        let (mut guard, stack) = region.split_at(PAGE_SIZE);
+        // A write-only page may not be accessed at all, as the Rust standard
+        // library also relies on for its guard pages. Nothing else holds the
+        // guard page, so nothing can touch it afterwards.
+        unsafe { guard.protect(MemoryFlags::W)? };
+        Ok(GuardedStack {
+            _guard: guard,
+            stack,
+            canary: false,
+        })
+    }
+
+    /// Writes a canary to the bottom of the stack, to be checked when the
+    /// thread is joined.
+    pub fn with_canary(mut self) -> Self {
+        for (offset, byte) in self.stack[..CANARY_LEN].iter_mut().enumerate() {
+            *byte = canary_byte(offset);
+        }
+        self.canary = true;
+        self
+    }
+
+    /// Returns the memory available to the thread, without the guard page.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.stack
+    }
+
+    /// Returns `JoinError::StackOverflow` for `thread_id` if the stack has a
+    /// canary and it has been overwritten. The thread must have exited.
+    pub(crate) fn check_canary(&self, thread_id: ThreadId) -> Result<(), JoinError> {
+        let intact = self.stack[..CANARY_LEN]
+            .iter()
+            .enumerate()
+            .all(|(offset, &byte)| byte == canary_byte(offset));
+        if self.canary && !intact {
+            return Err(JoinError::StackOverflow(thread_id));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "unstable_mem")]
+impl AsMut<[u8]> for GuardedStack {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_bytes_mut()
+    }
+}
+
+/// Creates a thread on a [GuardedStack], with up to four arguments.
+#[cfg(feature = "unstable_mem")]
+pub fn create_thread_guarded(
+    start: *mut usize,
+    mut stack: GuardedStack,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> Result<GuardedThread, Error> {
+    // The stack is kept by the handle until the thread has exited.
+    let thread_id = unsafe {
+        create_thread_raw(
+            start as usize,
+            stack.as_bytes_mut(),
+            [arg0, arg1, arg2, arg3],
+        )?
+    };
+    Ok(GuardedThread {
+        thread_id,
+        stack: Some(stack),
+    })
+}
+
+/// A thread running on a [GuardedStack].
+///
+/// Dropping this without joining the thread leaks its stack, since the thread
+/// may still be running on it.
+#[cfg(feature = "unstable_mem")]
+#[must_use = "the thread's stack is only released once it is joined"]
+pub struct GuardedThread {
+    thread_id: ThreadId,
+    stack: Option<GuardedStack>,
+}
+
+#[cfg(feature = "unstable_mem")]
+impl GuardedThread {
+    /// Returns the kernel's ID for the thread.
+    pub fn thread_id(&self) -> ThreadId {
+        self.thread_id
+    }
+
+    /// Waits for the thread to exit and returns its exit code, then releases
+    /// its stack.
+    ///
+    /// Returns `JoinError::StackOverflow` if the stack has a canary and the
+    /// thread overwrote it.
+    pub fn join(mut self) -> Result<usize, JoinError> {
+        let result = join_thread(self.thread_id)?;
+        if let Some(stack) = self.stack.take() {
+            stack.check_canary(self.thread_id)?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(feature = "unstable_mem")]
+impl Drop for GuardedThread {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack.take() {
+            // The thread may still be using it.
+            core::mem::forget(stack);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    #[cfg(feature = "unstable_mem")]
+    use std::format;
+
+    use super::*;
+
+    #[test]
+    fn static_thread_stacks() {
+        extern "C" fn add(a: usize, b: usize, _: usize, _: usize) -> usize {
+            a + b
+        }
+        static TABLE: ThreadTable<2, 1> = ThreadTable::new();
+
+        let first = TABLE.spawn(add as *mut usize, [1, 2, 0, 0]).unwrap();
+        let second = TABLE.spawn(add as *mut usize, [3, 4, 0, 0]).unwrap();
+        assert!(matches!(
+            TABLE.spawn(add as *mut usize, [0; 4]),
+            Err(Error::ThreadNotAvailable)
+        ));
+        assert_eq!(first.join().unwrap(), 3);
+        let third = TABLE.spawn(add as *mut usize, [5, 6, 0, 0]).unwrap();
+        assert_eq!(second.join().unwrap(), 7);
+        assert_eq!(third.join().unwrap(), 11);
+
+        let stack = Box::leak(Box::new(PageBuf::<1>::new()));
+        let tid =
+            create_thread_static(add as *mut usize, stack.as_bytes_mut(), 8, 9, 0, 0).unwrap();
+        assert_eq!(join_thread(tid).unwrap(), 17);
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    #[test]
+    fn guarded_stacks() {
+        extern "C" fn add(a: usize, b: usize, _: usize, _: usize) -> usize {
+            a + b
+        }
+        extern "C" fn overflow(bottom: usize, _: usize, _: usize, _: usize) -> usize {
+            unsafe { core::ptr::with_exposed_provenance_mut::<u8>(bottom).write(0) };
+            0
+        }
+
+        assert!(matches!(GuardedStack::new(0), Err(Error::InvalidArgument)));
+        let stack = GuardedStack::new(1).unwrap().with_canary();
+        let thread = create_thread_guarded(add as *mut usize, stack, 2, 3, 0, 0).unwrap();
+        assert_eq!(thread.join().unwrap(), 5);
+
+        let mut stack = GuardedStack::new(1).unwrap().with_canary();
+        assert_eq!(stack.as_bytes_mut().len(), 4096);
+        let bottom = stack.as_bytes_mut().as_mut_ptr().expose_provenance();
+        let thread = create_thread_guarded(overflow as *mut usize, stack, bottom, 0, 0, 0).unwrap();
+        let thread_id: usize = thread.thread_id().into();
+        let error = thread.join().unwrap_err();
+        assert!(matches!(error, JoinError::StackOverflow(_)));
+        assert_eq!(
+            format!("{}", error),
+            format!("thread {} overflowed its stack", thread_id)
+        );
+
+        let handle = crate::thread::Builder::new()
+            .stack_canary(true)
+            .spawn(|| 7)
+            .unwrap();
+        assert_eq!(handle.join().unwrap(), 7);
+    }
+}
@@ -0,0 +1,257 @@
+//! Client for the Xous ticktimer server, which is the source of time for
+//! every process. Time is counted in milliseconds since the system started.
+//!
+//! The opcodes match `api::Opcode` in `services/xous-ticktimer/src/api.rs` of
+//! xous-core.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+pub use core::time::Duration;
+
+use crate::{Connection, Error, blocking_scalar, scalar};
+
+mod opcode {
+    pub const ELAPSED_MS: usize = 0;
+    pub const SLEEP_MS: usize = 1;
+    pub const PING_WDT: usize = 4;
+    pub const LOCK_MUTEX: usize = 6;
+    pub const UNLOCK_MUTEX: usize = 7;
+    pub const WAIT_FOR_CONDITION: usize = 8;
+    pub const NOTIFY_CONDITION: usize = 9;
+    pub const FREE_MUTEX: usize = 10;
+    pub const FREE_CONDITION: usize = 11;
+}
+
+static TICKTIMER_CONNECTION: AtomicU32 = AtomicU32::new(0);
+
+/// Returns a `Connection` to the ticktimer server. If the ticktimer has not
+/// been started, then this call will block until it has. The `Connection` is
+/// shared among all callers in a process, so it is safe to call this multiple
+/// times.
+pub fn ticktimer() -> Result<Connection, Error> {
+    let cid = TICKTIMER_CONNECTION.load(Ordering::Relaxed);
+    if cid != 0 {
+        return Ok(cid.into());
+    }
+
+    let cid = crate::connect("ticktimer-server".try_into().unwrap())?;
+    TICKTIMER_CONNECTION.store(cid.into(), Ordering::Relaxed);
+    Ok(cid)
+}
+
+/// Returns a `Connection` to the ticktimer server, or `None` if it has not
+/// been started yet. Once this has returned a connection, `elapsed_ms` and
+/// `sleep_ms` will not block waiting for the server.
+pub(crate) fn try_ticktimer() -> Result<Option<Connection>, Error> {
+    let cid = TICKTIMER_CONNECTION.load(Ordering::Relaxed);
+    if cid != 0 {
+        return Ok(Some(cid.into()));
+    }
+
+    let Some(cid) = crate::try_connect("ticktimer-server".try_into().unwrap())? else {
+        return Ok(None);
+    };
+    TICKTIMER_CONNECTION.store(cid.into(), Ordering::Relaxed);
+    Ok(Some(cid))
+}
+
+/// Returns the number of milliseconds since the system started.
+pub fn elapsed_ms() -> Result<u64, Error> {
+    let result = blocking_scalar(ticktimer()?, [opcode::ELAPSED_MS, 0, 0, 0, 0])?;
+    Ok(result[0] as u64 | ((result[1] as u64) << 32))
+}
+
+/// Suspends the current thread for at least `ms` milliseconds.
+pub fn sleep_ms(ms: usize) -> Result<(), Error> {
+    blocking_scalar(ticktimer()?, [opcode::SLEEP_MS, ms, 0, 0, 0])?;
+    Ok(())
+}
+
+/// Suspends the current thread for at least `duration`, rounded up to the
+/// next millisecond.
+pub fn sleep(duration: Duration) -> Result<(), Error> {
+    let mut ms = duration.as_millis();
+    if !duration.subsec_nanos().is_multiple_of(1_000_000) {
+        ms += 1;
+    }
+    // Sleep in chunks that fit in a register.
+    while ms > 0 {
+        let chunk = usize::try_from(ms).unwrap_or(usize::MAX);
+        sleep_ms(chunk)?;
+        ms -= chunk as u128;
+    }
+    Ok(())
+}
+
+/// Resets the watchdog timer, on systems where it is enabled.
+pub fn ping_watchdog() -> Result<(), Error> {
+    scalar(ticktimer()?, [opcode::PING_WDT, 0, 0, 0, 0])
+}
+
+// The ticktimer also keeps the wait queues behind `sync`. Mutexes and
+// condition variables are identified by a cookie, which is the address of the
+// object in this process.
+
+/// Blocks until `unlock_mutex` is called for `cookie`. If it was already
+/// called, with no thread waiting, this returns immediately.
+pub(crate) fn lock_mutex(cookie: usize) -> Result<(), Error> {
+    blocking_scalar(ticktimer()?, [opcode::LOCK_MUTEX, cookie, 0, 0, 0])?;
+    Ok(())
+}
+
+/// Wakes one thread blocked in `lock_mutex` for `cookie`, or lets the next
+/// one through without blocking.
+pub(crate) fn unlock_mutex(cookie: usize) -> Result<(), Error> {
+    blocking_scalar(ticktimer()?, [opcode::UNLOCK_MUTEX, cookie, 0, 0, 0])?;
+    Ok(())
+}
+
+/// Blocks until `notify_condition` is called for `cookie`, or until `ms`
+/// milliseconds have passed. A timeout of `0` waits forever. Returns `true` if
+/// the thread was notified, and `false` if it timed out.
+pub(crate) fn wait_for_condition(cookie: usize, ms: usize) -> Result<bool, Error> {
+    let result = blocking_scalar(ticktimer()?, [opcode::WAIT_FOR_CONDITION, cookie, ms, 0, 0])?;
+    Ok(result[0] == 0)
+}
+
+/// Wakes up to `count` threads that are blocked in `wait_for_condition` for
+/// `cookie`, returning how many were woken.
+pub(crate) fn notify_condition(cookie: usize, count: usize) -> Result<usize, Error> {
+    let result = blocking_scalar(
+        ticktimer()?,
+        [opcode::NOTIFY_CONDITION, cookie, count, 0, 0],
+    )?;
+    Ok(result[0])
+}
+
+/// Releases any state the ticktimer holds for the mutex `cookie`.
+pub(crate) fn free_mutex(cookie: usize) -> Result<(), Error> {
+    scalar(ticktimer()?, [opcode::FREE_MUTEX, cookie, 0, 0, 0])
+}
+
+/// Releases any state the ticktimer holds for the condition `cookie`.
+pub(crate) fn free_condition(cookie: usize) -> Result<(), Error> {
+    scalar(ticktimer()?, [opcode::FREE_CONDITION, cookie, 0, 0, 0])
+}
+
+/// A point in time, measured by the ticktimer with millisecond precision.
+/// Instants only ever increase, so they may be compared and subtracted to
+/// measure how much time has passed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ms: u64,
+}
+
+impl Instant {
+    /// Returns the current time.
+    pub fn now() -> Result<Instant, Error> {
+        Ok(Instant { ms: elapsed_ms()? })
+    }
+
+    /// Returns the time since the system started that this instant represents.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_millis(self.ms)
+    }
+
+    /// Returns the time that has passed since this instant.
+    pub fn elapsed(&self) -> Result<Duration, Error> {
+        Ok(Instant::now()?.saturating_duration_since(*self))
+    }
+
+    /// Returns the time from `earlier` to this instant, or `None` if `earlier`
+    /// is later than this instant.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.ms.checked_sub(earlier.ms).map(Duration::from_millis)
+    }
+
+    /// Returns the time from `earlier` to this instant, or zero if `earlier`
+    /// is later than this instant.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    /// Returns the instant `duration` after this one, truncated to whole
+    /// milliseconds, or `None` if that cannot be represented.
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let ms = u64::try_from(duration.as_millis()).ok()?;
+        Some(Instant {
+            ms: self.ms.checked_add(ms)?,
+        })
+    }
+
+    /// Returns the instant `duration` before this one, truncated to whole
+    /// milliseconds, or `None` if that is before the system started.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let ms = u64::try_from(duration.as_millis()).ok()?;
+        Some(Instant {
+            ms: self.ms.checked_sub(ms)?,
+        })
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// # Panics
+    ///
+    /// Panics if the result cannot be represented.
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl core::ops::AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl core::ops::Sub<Duration> for Instant {
+    type Output = Instant;
+
+    /// # Panics
+    ///
+    /// Panics if the result is before the system started.
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl core::ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    /// Returns zero if `earlier` is later than this instant.
+    fn sub(self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use core::time::Duration;
+
+    use crate::ticktimer;
+
+    #[test]
+    fn ticktimer_time() {
+        let start = ticktimer::Instant::now().unwrap();
+        ticktimer::sleep(Duration::from_micros(14_500)).unwrap();
+        ticktimer::sleep_ms(0).unwrap();
+        let end = ticktimer::Instant::now().unwrap();
+        assert!(end - start >= Duration::from_millis(15));
+        assert!(start.elapsed().unwrap() >= end - start);
+        assert_eq!(start - end, Duration::ZERO);
+        assert_eq!(start.checked_duration_since(end), None);
+        assert_eq!(
+            (start + Duration::from_millis(5)) - start,
+            Duration::from_millis(5)
+        );
+        assert_eq!(
+            start.checked_sub(start.since_boot() + Duration::from_millis(1)),
+            None
+        );
+        assert!(ticktimer::elapsed_ms().unwrap() >= end.since_boot().as_millis() as u64);
+        ticktimer::ping_watchdog().unwrap();
+    }
+}
@@ -4,38 +4,23 @@
 //! is still in-progress.
 
 use crate::definitions::{
-    Connection, Error, InvokeType, MemoryFlags, Syscall, SyscallResult, ThreadId,
+    Connection, Error, MemoryFlags, PAGE_SIZE, PageAligned, Syscall, SyscallResult,
 };
-use crate::syscall;
-extern crate alloc;
-use alloc::boxed::Box;
+use crate::{Message, SendMode, syscall};
 
 /// Move the buffer to the server, blocking if
 /// the mailbox is full.
 pub fn r#move(
     connection: Connection,
     opcode: usize,
-    data: Box<[u8]>,
+    data: MappedRegion<u8>,
     arg1: usize,
     arg2: usize,
 ) -> Result<(), Error> {
-    let (addr, len) = (data.as_ptr() as usize, data.len());
-    // Memory will be moved even if this call fails
-    core::mem::forget(data);
-
-    unsafe {
-        syscall(
-            Syscall::SendMessage,
-            connection.0 as _,
-            InvokeType::Move as _,
-            opcode,
-            addr,
-            len,
-            arg1,
-            arg2,
-        )?
-    };
-    Ok(())
+    Message::r#move(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Block)
 }
 
 /// Attempt to mutably lend the buffer to the server.
@@ -43,27 +28,244 @@ pub fn r#move(
 pub fn try_move(
     connection: Connection,
     opcode: usize,
-    data: Box<[u8]>,
+    data: MappedRegion<u8>,
     arg1: usize,
     arg2: usize,
 ) -> Result<(), Error> {
-    let (addr, len) = (data.as_ptr() as usize, data.len());
-    // Memory will be moved even if this call fails
-    core::mem::forget(data);
+    Message::r#move(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Try)
+}
 
-    unsafe {
-        syscall(
-            Syscall::TrySendMessage,
-            connection.0 as _,
-            InvokeType::Move as _,
-            opcode,
-            addr,
-            len,
-            arg1,
-            arg2,
-        )?
-    };
-    Ok(())
+/// Move a page-aligned buffer to the server, blocking if the mailbox is full.
+pub fn move_pages(
+    connection: Connection,
+    opcode: usize,
+    data: HeapPageBuf,
+    arg1: usize,
+    arg2: usize,
+) -> Result<(), Error> {
+    Message::move_pages(data)
+        .opcode(opcode)
+        .args(arg1, arg2)
+        .send(connection, SendMode::Block)
+}
+
+/// A page-aligned buffer of memory mapped directly from the kernel, for
+/// buffers that are too large for the stack. The memory is zeroed when it is
+/// created, and is unmapped when this is dropped.
+pub struct HeapPageBuf {
+    ptr: core::ptr::NonNull<u8>,
+    len: usize,
+}
+
+impl HeapPageBuf {
+    /// Allocates a new zeroed buffer of `pages` pages.
+    pub fn new(pages: usize) -> Result<Self, Error> {
+        let size = pages.checked_mul(PAGE_SIZE).ok_or(Error::OutOfMemory)?;
+        if size == 0 {
+            return Err(Error::InvalidArgument);
+        }
+        let result = unsafe {
+            syscall(
+                Syscall::MapMemory,
+                0,
+                0,
+                size,
+                (MemoryFlags::R | MemoryFlags::W | MemoryFlags::RESERVE).bits(),
+                0,
+                0,
+                0,
+            )?
+        };
+        if result.0 != SyscallResult::MemoryRange as usize {
+            return Err(Error::InternalError);
+        }
+        let ptr = core::ptr::NonNull::new(core::ptr::with_exposed_provenance_mut(result.1))
+            .ok_or(Error::BadAddress)?;
+        Ok(HeapPageBuf { ptr, len: size })
+    }
+
+    /// Allocates a new buffer large enough to hold `data`, with `data` copied
+    /// to the start and the remainder zeroed.
+    pub fn from_slice(data: &[u8]) -> Result<Self, Error> {
+        let mut buf = Self::new(data.len().div_ceil(PAGE_SIZE).max(1))?;
+        buf.as_bytes_mut()[..data.len()].copy_from_slice(data);
+        Ok(buf)
+    }
+}
+
+impl PageAligned for HeapPageBuf {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl core::ops::Deref for HeapPageBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl core::ops::DerefMut for HeapPageBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_bytes_mut()
+    }
+}
+
+impl Drop for HeapPageBuf {
+    fn drop(&mut self) {
+        let _ = unsafe {
+            syscall(
+                Syscall::UnmapMemory,
+                self.ptr.as_ptr() as usize,
+                self.len,
+                0,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+    }
+}
+
+/// A range of memory mapped from the kernel with `map_memory`, viewed as a
+/// slice of `T`. The memory is unmapped when this is dropped. The elements
+/// are not dropped first, since the memory may belong to hardware.
+pub struct MappedRegion<T> {
+    ptr: core::ptr::NonNull<T>,
+    len: usize,
+    flags: MemoryFlags,
+}
+
+// Safety: the region owns its memory, just as a `Box<[T]>` would.
+unsafe impl<T: Send> Send for MappedRegion<T> {}
+unsafe impl<T: Sync> Sync for MappedRegion<T> {}
+
+impl<T> MappedRegion<T> {
+    /// Takes ownership of `len` elements of mapped memory starting at `ptr`,
+    /// which currently have the permissions given by `flags`.
+    ///
+    /// # Safety
+    ///
+    /// The memory must have been mapped by the kernel, must not be owned by
+    /// anything else, and must start and end on a page boundary, as the
+    /// memory returned by [MappedRegion::into_raw] does.
+    ///
+    /// Returns `Error::BadAddress` if `ptr` is null.
+    pub unsafe fn from_raw(ptr: *mut [T], flags: MemoryFlags) -> Result<Self, Error> {
+        Ok(MappedRegion {
+            ptr: core::ptr::NonNull::new(ptr.cast::<T>()).ok_or(Error::BadAddress)?,
+            len: ptr.len(),
+            flags,
+        })
+    }
+
+    /// Gives up ownership of the memory without unmapping it, returning the
+    /// range it occupies.
+    pub fn into_raw(self) -> *mut [T] {
+        let range = core::ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), self.len);
+        core::mem::forget(self);
+        range
+    }
+
+    /// Returns the permissions the memory was mapped with, less any that have
+    /// since been removed with [MappedRegion::protect].
+    pub fn flags(&self) -> MemoryFlags {
+        self.flags
+    }
+
+    /// Splits the region in two at element `mid`, so that each part may be
+    /// unmapped or moved on its own.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mid` is greater than the length, or if the split does not
+    /// fall on a page boundary.
+    pub fn split_at(self, mid: usize) -> (Self, Self) {
+        assert!(mid <= self.len, "split point is out of bounds");
+        assert!(
+            (mid * size_of::<T>()).is_multiple_of(PAGE_SIZE),
+            "split point is not on a page boundary"
+        );
+        let (ptr, len, flags) = (self.ptr, self.len, self.flags);
+        core::mem::forget(self);
+        (
+            MappedRegion {
+                ptr,
+                len: mid,
+                flags,
+            },
+            MappedRegion {
+                // `mid` is within the region.
+                ptr: unsafe { ptr.add(mid) },
+                len: len - mid,
+                flags,
+            },
+        )
+    }
+
+    /// Removes permissions from the memory, leaving only those in `flags`.
+    /// Flags may only be removed and may never be added.
+    ///
+    /// # Safety
+    ///
+    /// The memory may become inaccessible or have its mutability removed. It is
+    /// up to the caller to ensure that the region is only accessed as `flags`
+    /// allows, otherwise the program will crash.
+    pub unsafe fn protect(&mut self, flags: MemoryFlags) -> Result<(), Error> {
+        unsafe {
+            syscall(
+                Syscall::UpdateMemoryFlags,
+                self.ptr.as_ptr() as usize,
+                self.len * size_of::<T>(),
+                flags.bits(),
+                0, // Process ID flag is currently None
+                0,
+                0,
+                0,
+            )?
+        };
+        self.flags = flags;
+        Ok(())
+    }
+}
+
+impl<T> core::ops::Deref for MappedRegion<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> core::ops::DerefMut for MappedRegion<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for MappedRegion<T> {
+    fn drop(&mut self) {
+        let _ = unsafe {
+            syscall(
+                Syscall::UnmapMemory,
+                self.ptr.as_ptr() as usize,
+                self.len * size_of::<T>(),
+                0,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+    }
 }
 
 /// Allocates memory from the system.
@@ -72,6 +274,10 @@ pub fn try_move(
 /// ensure memory is allocated at specific offsets, otherwise the kernel will
 /// select an address.
 ///
+/// Returns `Error::InvalidArgument` if `T` is zero-sized, and
+/// `Error::OutOfMemory` if `count` elements of `T` do not fit in the address
+/// space.
+///
 /// # Safety
 ///
 /// This function is safe unless a virtual address is specified. In that case,
@@ -82,13 +288,19 @@ pub unsafe fn map_memory<T>(
     virt: Option<core::ptr::NonNull<T>>,
     count: usize,
     flags: MemoryFlags,
-) -> Result<Box<[T]>, Error> {
+) -> Result<MappedRegion<T>, Error> {
+    if size_of::<T>() == 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let size = count
+        .checked_mul(size_of::<T>())
+        .ok_or(Error::OutOfMemory)?;
     let result = unsafe {
         syscall(
             Syscall::MapMemory,
             phys.map(|p| p.as_ptr() as usize).unwrap_or_default(),
             virt.map(|p| p.as_ptr() as usize).unwrap_or_default(),
-            count * size_of::<T>(),
+            size,
             flags.bits(),
             0,
             0,
@@ -102,18 +314,20 @@ pub unsafe fn map_memory<T>(
 
     let start = core::ptr::with_exposed_provenance_mut::<T>(result.1);
     let len = result.2 / size_of::<T>();
-    Ok(unsafe { Box::from_raw(core::slice::from_raw_parts_mut(start, len)) })
+    unsafe { MappedRegion::from_raw(core::ptr::slice_from_raw_parts_mut(start, len), flags) }
 }
 
-/// Destroys the given memory, returning it to the compiler.
+/// Destroys the given memory, returning it to the kernel. Dropping the region
+/// does the same, but ignores any error.
 ///
 /// Safety: The memory pointed to by `range` should not be used after this
 /// function returns, even if this function returns Err().
-pub unsafe fn unmap_memory<T>(range: Box<[T]>) -> Result<(), Error> {
+pub unsafe fn unmap_memory<T>(range: MappedRegion<T>) -> Result<(), Error> {
+    let range = range.into_raw();
     unsafe {
         syscall(
             Syscall::UnmapMemory,
-            range.as_ptr() as usize,
+            range.cast::<T>() as usize,
             range.len() * size_of::<T>(),
             0,
             0,
@@ -122,8 +336,6 @@ pub unsafe fn unmap_memory<T>(range: Box<[T]>) -> Result<(), Error> {
             0,
         )?
     };
-    // Memory has been freed by the kernel
-    core::mem::forget(range);
     Ok(())
 }
 
@@ -136,51 +348,99 @@ pub unsafe fn unmap_memory<T>(range: Box<[T]>) -> Result<(), Error> {
 /// mutability removed. It is up to the caller to ensure that the flags specified
 /// by `new_flags` are upheld, otherwise the program will crash.
 pub unsafe fn update_memory_flags<T>(
-    range: &mut Box<[T]>,
+    range: &mut MappedRegion<T>,
     new_flags: MemoryFlags,
 ) -> Result<(), Error> {
-    unsafe {
-        syscall(
-            Syscall::UpdateMemoryFlags,
-            range.as_mut_ptr() as _,
-            range.len() * size_of::<T>(),
-            new_flags.bits(),
-            0, // Process ID flag is currently None
-            0,
-            0,
-            0,
-        )?
-    };
-    Ok(())
+    unsafe { range.protect(new_flags) }
 }
 
-/// Creates a thread with a given stack and up to four arguments.
-pub fn create_thread(
-    start: *mut usize,
-    stack: Box<[u8]>,
-    arg0: usize,
-    arg1: usize,
-    arg2: usize,
-    arg3: usize,
-) -> Result<ThreadId, Error> {
-    let result = unsafe {
-        syscall(
-            Syscall::CreateThread,
-            start as usize,
-            stack.as_ptr() as _,
-            stack.len(),
-            arg0,
-            arg1,
-            arg2,
-            arg3,
-        )?
-    };
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    extern crate std;
 
-    // Stack is now owned by the thread
-    core::mem::forget(stack);
+    use std::thread;
 
-    if result.0 != SyscallResult::ThreadId as usize {
-        return Err(Error::InternalError);
+    use crate::mock::test_util::address;
+    use crate::*;
+
+    #[test]
+    fn map_and_unmap_memory() {
+        let mut range =
+            unsafe { map_memory::<u8>(None, None, 100, MemoryFlags::R | MemoryFlags::W) }.unwrap();
+        assert_eq!(range.len(), 4096);
+        assert_eq!(range.as_ptr() as usize % 4096, 0);
+        assert!(range.iter().all(|&b| b == 0));
+        range[5] = 1;
+        assert_eq!(range.flags(), MemoryFlags::R | MemoryFlags::W);
+        unsafe { range.protect(MemoryFlags::R) }.unwrap();
+        assert_eq!(range.flags(), MemoryFlags::R);
+        let addr = range.as_ptr() as usize;
+        unsafe { unmap_memory(range) }.unwrap();
+
+        let result = unsafe { syscall(Syscall::UnmapMemory, addr, 4096, 0, 0, 0, 0, 0) };
+        assert!(matches!(result, Err(Error::BadAddress)));
+
+        // Each half of a split region is unmapped on its own when dropped.
+        let range = unsafe { map_memory::<u32>(None, None, 3000, MemoryFlags::R | MemoryFlags::W) }
+            .unwrap();
+        assert_eq!(range.len(), 3072);
+        let addr = range.as_ptr() as usize;
+        let (low, high) = range.split_at(1024);
+        assert_eq!((low.len(), high.len()), (1024, 2048));
+        assert_eq!(high.as_ptr() as usize, addr + 4096);
+        drop(low);
+        let high = unsafe { MappedRegion::from_raw(high.into_raw(), MemoryFlags::R) }.unwrap();
+        let result = unsafe { syscall(Syscall::UnmapMemory, addr, 4096, 0, 0, 0, 0, 0) };
+        assert!(matches!(result, Err(Error::BadAddress)));
+        assert_eq!(high[2047], 0);
+        drop(high);
+        let result = unsafe { syscall(Syscall::UnmapMemory, addr + 4096, 4096, 0, 0, 0, 0, 0) };
+        assert!(matches!(result, Err(Error::BadAddress)));
+    }
+
+    #[test]
+    fn map_memory_rejects_bad_sizes() {
+        let flags = MemoryFlags::R | MemoryFlags::W;
+        assert!(matches!(
+            unsafe { map_memory::<()>(None, None, 1, flags) },
+            Err(Error::InvalidArgument)
+        ));
+        assert!(matches!(
+            unsafe { map_memory::<u32>(None, None, usize::MAX / 2, flags) },
+            Err(Error::OutOfMemory)
+        ));
+        let null = core::ptr::slice_from_raw_parts_mut(core::ptr::null_mut::<u8>(), 4096);
+        assert!(matches!(
+            unsafe { MappedRegion::from_raw(null, flags) },
+            Err(Error::BadAddress)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "split point is not on a page boundary")]
+    fn mapped_region_split_must_be_page_aligned() {
+        let range =
+            unsafe { map_memory::<u8>(None, None, 8192, MemoryFlags::R | MemoryFlags::W) }.unwrap();
+        let _ = range.split_at(100);
+    }
+
+    #[test]
+    fn move_heap_pages() {
+        let sid = create_server_with_address(address("mock-move")).unwrap();
+        let server = thread::spawn(move || {
+            let envelope = receive_message(sid).unwrap();
+            assert_eq!(envelope.invoke_type(), InvokeType::Move);
+            assert_eq!(envelope.buf().unwrap()[0], 9);
+            let (_, body) = envelope.defer();
+            if let MessageBody::Memory(mem) = body {
+                unsafe { syscall(Syscall::UnmapMemory, mem.addr(), mem.len(), 0, 0, 0, 0, 0) }
+                    .unwrap();
+            }
+        });
+        let cid = connect(address("mock-move")).unwrap();
+        let buf = HeapPageBuf::from_slice(&[9; 10]).unwrap();
+        move_pages(cid, 1, buf, 0, 0).unwrap();
+        server.join().unwrap();
+        destroy_server(sid).unwrap();
     }
-    Ok(result.1.into())
 }
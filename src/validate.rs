@@ -0,0 +1,262 @@
+//! Argument checks for memory messages. With the `validate` feature enabled in
+//! a debug build, `lend`, `lend_mut` and `move` panic with a description of the
+//! offending argument instead of letting the kernel reject it with a bare
+//! `BadAlignment` or `BadAddress`. Otherwise these checks compile to nothing.
+//!
+//! Lends that are in flight are tracked so that a buffer which overlaps one
+//! that is currently lent mutably, or which is lent mutably while part of it
+//! is already lent, is caught as well. Up to 32 lends are tracked at once.
+//! Once that many are in flight, further lends are still checked against the
+//! tracked ones but are not tracked themselves, so an overlap with one of
+//! them goes unreported.
+
+/// Returned by `check_lend`, and removes the lend from the set of lends in
+/// flight when dropped. Keep it alive until the lend has been returned.
+pub(crate) struct LendGuard {
+    #[cfg(all(feature = "validate", debug_assertions))]
+    slot: Option<usize>,
+}
+
+impl LendGuard {
+    /// A guard for a message that does not lend a buffer.
+    pub(crate) fn none() -> Self {
+        LendGuard {
+            #[cfg(all(feature = "validate", debug_assertions))]
+            slot: None,
+        }
+    }
+}
+
+/// Validates a buffer that is about to be lent by `call`.
+#[inline]
+pub(crate) fn check_lend(call: &str, data: *const u8, len: usize, mutable: bool) -> LendGuard {
+    #[cfg(all(feature = "validate", debug_assertions))]
+    {
+        check_range(call, data, len);
+        LendGuard {
+            slot: tracking::insert(call, data as usize, len, mutable),
+        }
+    }
+    #[cfg(not(all(feature = "validate", debug_assertions)))]
+    {
+        let _ = (call, data, len, mutable);
+        LendGuard {}
+    }
+}
+
+/// Validates a buffer that is about to be moved by `call`.
+#[cfg(feature = "unstable_mem")]
+#[inline]
+pub(crate) fn check_move(call: &str, data: *const u8, len: usize) {
+    #[cfg(all(feature = "validate", debug_assertions))]
+    {
+        check_range(call, data, len);
+        tracking::check_unlent(call, data as usize, len);
+    }
+    #[cfg(not(all(feature = "validate", debug_assertions)))]
+    let _ = (call, data, len);
+}
+
+#[cfg(all(feature = "validate", debug_assertions))]
+fn check_range(call: &str, data: *const u8, len: usize) {
+    use crate::PAGE_SIZE;
+
+    // An empty slice has a dangling pointer, and never reaches the server's
+    // address space, so there is nothing to check.
+    if len == 0 {
+        return;
+    }
+    if data.is_null() {
+        panic!("{}: `data` is a null pointer", call);
+    }
+    let addr = data as usize;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        panic!(
+            "{}: `data` must start on a page boundary, but is at {:#x}",
+            call, addr
+        );
+    }
+    if !len.is_multiple_of(PAGE_SIZE) {
+        panic!(
+            "{}: `data` must be a multiple of {} bytes long, but is {} bytes",
+            call, PAGE_SIZE, len
+        );
+    }
+}
+
+impl Drop for LendGuard {
+    fn drop(&mut self) {
+        #[cfg(all(feature = "validate", debug_assertions))]
+        if let Some(slot) = self.slot {
+            tracking::remove(slot);
+        }
+    }
+}
+
+#[cfg(all(feature = "validate", debug_assertions))]
+mod tracking {
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    /// The number of lends that can be tracked at once. Further lends are still
+    /// checked against the ones being tracked, but are not tracked themselves.
+    const MAX_LENDS: usize = 32;
+
+    #[derive(Copy, Clone)]
+    struct Lend {
+        addr: usize,
+        len: usize,
+        mutable: bool,
+    }
+
+    struct Lends {
+        locked: AtomicBool,
+        lends: UnsafeCell<[Option<Lend>; MAX_LENDS]>,
+    }
+
+    // Safety: `lends` is only accessed while `locked` is held.
+    unsafe impl Sync for Lends {}
+
+    static LENDS: Lends = Lends {
+        locked: AtomicBool::new(false),
+        lends: UnsafeCell::new([None; MAX_LENDS]),
+    };
+
+    fn with_lends<R>(f: impl FnOnce(&mut [Option<Lend>; MAX_LENDS]) -> R) -> R {
+        while LENDS
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *LENDS.lends.get() });
+        LENDS.locked.store(false, Ordering::Release);
+        result
+    }
+
+    fn find_conflict(
+        lends: &[Option<Lend>],
+        addr: usize,
+        len: usize,
+        mutable: bool,
+    ) -> Option<Lend> {
+        lends.iter().flatten().copied().find(|lend| {
+            (mutable || lend.mutable) && addr < lend.addr + lend.len && lend.addr < addr + len
+        })
+    }
+
+    fn report(call: &str, addr: usize, len: usize, existing: Lend) -> ! {
+        panic!(
+            "{}: `data` at {:#x}..{:#x} overlaps a buffer at {:#x}..{:#x} that is already {}lent",
+            call,
+            addr,
+            addr + len,
+            existing.addr,
+            existing.addr + existing.len,
+            if existing.mutable { "mutably " } else { "" }
+        );
+    }
+
+    pub(super) fn insert(call: &str, addr: usize, len: usize, mutable: bool) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+        let conflict = with_lends(|lends| {
+            if let Some(existing) = find_conflict(lends, addr, len, mutable) {
+                return Err(existing);
+            }
+            let slot = lends.iter().position(Option::is_none);
+            if let Some(slot) = slot {
+                lends[slot] = Some(Lend { addr, len, mutable });
+            }
+            Ok(slot)
+        });
+        conflict.unwrap_or_else(|existing| report(call, addr, len, existing))
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    pub(super) fn check_unlent(call: &str, addr: usize, len: usize) {
+        if let Some(existing) = with_lends(|lends| find_conflict(lends, addr, len, true)) {
+            report(call, addr, len, existing);
+        }
+    }
+
+    pub(super) fn remove(slot: usize) {
+        with_lends(|lends| lends[slot] = None);
+    }
+}
+
+#[cfg(all(test, feature = "validate", debug_assertions))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::PAGE_SIZE;
+
+    /// An address in the upper half of the address space, far away from any
+    /// real buffer, so that these lends do not overlap the ones made by other
+    /// tests.
+    fn fake(page: usize) -> *const u8 {
+        core::ptr::without_provenance(usize::MAX / 2 + 1 + page * PAGE_SIZE)
+    }
+
+    #[test]
+    #[should_panic(expected = "lend: `data` must start on a page boundary")]
+    fn misaligned_lend() {
+        let _ = check_lend("lend", fake(0).wrapping_add(1), PAGE_SIZE, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "lend_mut: `data` is a null pointer")]
+    fn null_lend() {
+        let _ = check_lend("lend_mut", core::ptr::null(), PAGE_SIZE, true);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "lend: `data` must be a multiple of 4096 bytes long, but is 100 bytes"
+    )]
+    fn partial_page_lend() {
+        let _ = check_lend("lend", fake(1), 100, false);
+    }
+
+    #[test]
+    #[should_panic(expected = "that is already mutably lent")]
+    fn overlapping_lend() {
+        let _first = check_lend("lend_mut", fake(0x10), 2 * PAGE_SIZE, true);
+        let _ = check_lend("lend", fake(0x11), PAGE_SIZE, false);
+    }
+
+    #[cfg(feature = "unstable_mem")]
+    #[test]
+    #[should_panic(expected = "move: `data` at")]
+    fn moving_lent_memory() {
+        let _lend = check_lend("lend", fake(0x20), PAGE_SIZE, false);
+        check_move("move", fake(0x20), PAGE_SIZE);
+    }
+
+    #[test]
+    fn lends_beyond_the_table_are_not_tracked() {
+        // Fill whatever is left of the table, up to the first lend that is
+        // not tracked.
+        let mut guards = std::vec::Vec::new();
+        let untracked = loop {
+            let page = fake(0x100 + guards.len());
+            let guard = check_lend("lend_mut", page, PAGE_SIZE, true);
+            if guard.slot.is_none() {
+                break page;
+            }
+            guards.push(guard);
+        };
+        assert!(!guards.is_empty());
+
+        // An overlap with the untracked lend is not caught, but the tracked
+        // lends are still checked.
+        let _ = check_lend("lend", untracked, PAGE_SIZE, false);
+        let conflict = std::panic::catch_unwind(|| {
+            let _ = check_lend("lend", fake(0x100), PAGE_SIZE, false);
+        });
+        assert!(conflict.is_err());
+    }
+}